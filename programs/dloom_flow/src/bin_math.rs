@@ -0,0 +1,1282 @@
+// FILE: bin_math.rs
+//
+// Runtime-free DLMM math. Nothing in here touches accounts or the Solana runtime, so
+// off-chain routers and backtesters can call the exact functions the program executes.
+
+use std::collections::BTreeMap;
+use std::ops::Bound;
+
+use crate::constants::{
+    BASIS_POINT_MAX, BINS_PER_ARRAY, BIN_ARRAY_BITMAP_WORDS, BITMAP_EXTENSION_WORDS, MAX_FEE_RATE,
+    PRECISION, VARIABLE_FEE_PRECISION,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MathError {
+    Overflow,
+    InvalidBinStep,
+    InvalidBinId,
+    InsufficientLiquidity,
+    BinArrayOutOfRange,
+    MissingBitmapExtension,
+}
+
+pub type MathResult<T> = std::result::Result<T, MathError>;
+
+pub fn get_price_at_bin(bin_id: i32, bin_step: u16) -> MathResult<u128> {
+    if bin_step == 0 {
+        return Err(MathError::InvalidBinStep);
+    }
+    let basis_point_step = bin_step as u128;
+    let power = bin_id.unsigned_abs() as u128;
+    let base = BASIS_POINT_MAX
+        .checked_add(basis_point_step)
+        .ok_or(MathError::Overflow)?;
+
+    let price_ratio = power_fp(base, power)?;
+
+    if bin_id >= 0 {
+        Ok(price_ratio)
+    } else {
+        PRECISION
+            .checked_mul(PRECISION)
+            .ok_or(MathError::Overflow)?
+            .checked_div(price_ratio)
+            .ok_or(MathError::Overflow)
+    }
+}
+
+fn power_fp(base: u128, exp: u128) -> MathResult<u128> {
+    let mut res = PRECISION;
+    let mut base_fp = base;
+    let mut exp_rem = exp;
+
+    if exp == 0 {
+        return Ok(PRECISION);
+    }
+
+    while exp_rem > 0 {
+        if exp_rem % 2 == 1 {
+            res = res
+                .checked_mul(base_fp)
+                .ok_or(MathError::Overflow)?
+                .checked_div(BASIS_POINT_MAX)
+                .ok_or(MathError::Overflow)?;
+        }
+        base_fp = base_fp
+            .checked_mul(base_fp)
+            .ok_or(MathError::Overflow)?
+            .checked_div(BASIS_POINT_MAX)
+            .ok_or(MathError::Overflow)?;
+        exp_rem /= 2;
+    }
+    Ok(res)
+}
+
+/// A bin's token inventory and the liquidity shares issued against it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BinReserves {
+    pub amount_a: u64,
+    pub amount_b: u64,
+    pub liquidity_supply: u128,
+}
+
+impl BinReserves {
+    pub fn is_empty(&self) -> bool {
+        self.amount_a == 0 && self.amount_b == 0
+    }
+
+    /// What the swap takes out of this bin: token B for A to B swaps, token A otherwise.
+    pub fn amount_out_available(&self, a_to_b: bool) -> u64 {
+        if a_to_b {
+            self.amount_b
+        } else {
+            self.amount_a
+        }
+    }
+}
+
+fn mul_div(amount: u128, numerator: u128, denominator: u128, round_up: bool) -> MathResult<u128> {
+    let product = amount.checked_mul(numerator).ok_or(MathError::Overflow)?;
+    if denominator == 0 {
+        return Err(MathError::Overflow);
+    }
+    Ok(if round_up {
+        product.div_ceil(denominator)
+    } else {
+        product / denominator
+    })
+}
+
+/// Tokens needed to mint `liquidity_amount` shares of a bin. A bin that already holds
+/// tokens is topped up in its current proportions (rounded up) so existing shares keep
+/// their value; an empty bin is seeded with token A above the active bin, token B below
+/// it and an even split of value in the active bin.
+pub fn calculate_required_for_bin(
+    active_bin_id: i32,
+    bin_id: i32,
+    bin_step: u16,
+    reserves: &BinReserves,
+    liquidity_amount: u128,
+) -> MathResult<(u128, u128)> {
+    let mut required_a: u128 = 0;
+    let mut required_b: u128 = 0;
+
+    if reserves.liquidity_supply > 0 && !reserves.is_empty() {
+        required_a = mul_div(reserves.amount_a as u128, liquidity_amount, reserves.liquidity_supply, true)?;
+        required_b = mul_div(reserves.amount_b as u128, liquidity_amount, reserves.liquidity_supply, true)?;
+    } else if bin_id > active_bin_id {
+        // Bins above the active price are denominated in Token A
+        required_a = liquidity_amount;
+    } else if bin_id < active_bin_id {
+        // Bins below the active price are denominated in Token B
+        let price = get_price_at_bin(bin_id, bin_step)?;
+        required_b = liquidity_amount
+            .checked_mul(price)
+            .ok_or(MathError::Overflow)?
+            .checked_div(PRECISION)
+            .ok_or(MathError::Overflow)?;
+    } else {
+        // The active bin can contain both tokens
+        let price = get_price_at_bin(bin_id, bin_step)?;
+        let liquidity_as_b = liquidity_amount / 2;
+        required_a = liquidity_amount
+            .checked_sub(liquidity_as_b)
+            .ok_or(MathError::Overflow)?;
+        required_b = liquidity_as_b
+            .checked_mul(price)
+            .ok_or(MathError::Overflow)?
+            .checked_div(PRECISION)
+            .ok_or(MathError::Overflow)?;
+    }
+
+    Ok((required_a, required_b))
+}
+
+/// Scales a reference distribution of liquidity down (or up) to what `amount_a` and
+/// `amount_b` can pay for. `reference_amounts[i]` are the tokens bin `i` needs for
+/// `reference_liquidity[i]`; every bin is scaled by the same factor, set by whichever token
+/// runs out first. Each total is padded by one unit per bin, and one unit per bin is held
+/// back from each budget, so the rounding in `calculate_required_for_bin` cannot take the
+/// final deposit above the amounts offered.
+pub fn scale_liquidity_to_amounts(
+    reference_liquidity: &[u128],
+    reference_amounts: &[(u128, u128)],
+    amount_a: u64,
+    amount_b: u64,
+) -> MathResult<Vec<u128>> {
+    let mut total_a: u128 = 0;
+    let mut total_b: u128 = 0;
+    for (required_a, required_b) in reference_amounts {
+        if *required_a > 0 {
+            total_a = total_a.checked_add(required_a + 1).ok_or(MathError::Overflow)?;
+        }
+        if *required_b > 0 {
+            total_b = total_b.checked_add(required_b + 1).ok_or(MathError::Overflow)?;
+        }
+    }
+
+    let bins = reference_amounts.len() as u128;
+    let budget_a = (amount_a as u128).saturating_sub(bins);
+    let budget_b = (amount_b as u128).saturating_sub(bins);
+    // The smaller of budget_a / total_a and budget_b / total_b, as a fraction
+    let (numerator, denominator) = match (total_a > 0, total_b > 0) {
+        (true, true) => {
+            let scaled_a = budget_a.checked_mul(total_b).ok_or(MathError::Overflow)?;
+            let scaled_b = budget_b.checked_mul(total_a).ok_or(MathError::Overflow)?;
+            if scaled_a <= scaled_b {
+                (budget_a, total_a)
+            } else {
+                (budget_b, total_b)
+            }
+        }
+        (true, false) => (budget_a, total_a),
+        (false, true) => (budget_b, total_b),
+        (false, false) => return Ok(vec![0; reference_liquidity.len()]),
+    };
+
+    reference_liquidity
+        .iter()
+        .map(|liquidity| mul_div(*liquidity, numerator, denominator, false))
+        .collect()
+}
+
+/// Reward growth per unit of liquidity from `elapsed` seconds of emission at `reward_rate`
+/// (scaled by `PRECISION`) into a bin with `liquidity_supply` shares. Nothing accrues to an
+/// empty bin.
+pub fn reward_growth_delta(reward_rate: u128, elapsed: u64, liquidity_supply: u128) -> MathResult<u128> {
+    if liquidity_supply == 0 {
+        return Ok(0);
+    }
+    mul_div(reward_rate, elapsed as u128, liquidity_supply, false)
+}
+
+/// Emission rate after `amount` is added to a reward whose next period runs `duration`
+/// seconds from `now`. Whatever the current period has not emitted yet rolls into the new one.
+pub fn reward_rate_after_funding(
+    reward_rate: u128,
+    reward_duration_end: i64,
+    now: i64,
+    amount: u64,
+    duration: u64,
+) -> MathResult<u128> {
+    let remaining = if now < reward_duration_end {
+        reward_rate
+            .checked_mul(reward_duration_end.abs_diff(now) as u128)
+            .ok_or(MathError::Overflow)?
+    } else {
+        0
+    };
+    (amount as u128)
+        .checked_mul(PRECISION)
+        .and_then(|funded| funded.checked_add(remaining))
+        .ok_or(MathError::Overflow)?
+        .checked_div(duration as u128)
+        .ok_or(MathError::Overflow)
+}
+
+/// Tokens `share` liquidity shares are worth at the bin's current composition, rounded down.
+pub fn calculate_withdrawal_for_bin(reserves: &BinReserves, share: u128) -> MathResult<(u128, u128)> {
+    if reserves.liquidity_supply == 0 {
+        return Ok((0, 0));
+    }
+    Ok((
+        mul_div(reserves.amount_a as u128, share, reserves.liquidity_supply, false)?,
+        mul_div(reserves.amount_b as u128, share, reserves.liquidity_supply, false)?,
+    ))
+}
+
+/// Fees earned by `share` units of liquidity since `checkpoint`. Saturates instead of
+/// failing so a stale checkpoint can never block a withdrawal.
+pub fn accrued_fee(fee_growth: u128, checkpoint: u128, share: u128) -> u64 {
+    fee_growth
+        .saturating_sub(checkpoint)
+        .checked_mul(share)
+        .unwrap_or(0)
+        .checked_div(PRECISION)
+        .unwrap_or(0) as u64
+}
+
+pub fn split_protocol_fee(fee: u128, protocol_fee_bps: u16) -> MathResult<(u128, u128)> {
+    let protocol_fee = fee
+        .checked_mul(protocol_fee_bps as u128)
+        .ok_or(MathError::Overflow)?
+        .checked_div(BASIS_POINT_MAX)
+        .ok_or(MathError::Overflow)?;
+    let lp_fee = fee.checked_sub(protocol_fee).ok_or(MathError::Overflow)?;
+    Ok((protocol_fee, lp_fee))
+}
+
+/// Gross input (rounded up) whose post-fee amount covers `amount_in_consumed`.
+pub fn amount_in_with_fee(amount_in_consumed: u128, fee_rate: u16) -> MathResult<u128> {
+    let fee_denominator = BASIS_POINT_MAX
+        .checked_sub(fee_rate as u128)
+        .ok_or(MathError::Overflow)?;
+    if fee_denominator == 0 {
+        return Err(MathError::Overflow);
+    }
+    Ok(amount_in_consumed
+        .checked_mul(BASIS_POINT_MAX)
+        .ok_or(MathError::Overflow)?
+        .div_ceil(fee_denominator))
+}
+
+/// Converts between the two tokens at `price`: A to B when `a_to_b`, B to A otherwise.
+pub fn convert_amount(amount: u128, price: u128, a_to_b: bool) -> MathResult<u128> {
+    if a_to_b {
+        amount
+            .checked_mul(price)
+            .ok_or(MathError::Overflow)?
+            .checked_div(PRECISION)
+            .ok_or(MathError::Overflow)
+    } else {
+        amount
+            .checked_mul(PRECISION)
+            .ok_or(MathError::Overflow)?
+            .checked_div(price)
+            .ok_or(MathError::Overflow)
+    }
+}
+
+/// Locates a bin as (bin array index, slot within the array). Bins sit on multiples of
+/// `bin_step`, so consecutive slots are `bin_step` ids apart.
+pub fn bin_array_location(bin_id: i32, bin_step: u16) -> MathResult<(i32, usize)> {
+    if bin_step == 0 {
+        return Err(MathError::InvalidBinStep);
+    }
+    if bin_id % bin_step as i32 != 0 {
+        return Err(MathError::InvalidBinId);
+    }
+    let ordinal = bin_id / bin_step as i32;
+    let index = ordinal.div_euclid(BINS_PER_ARRAY as i32);
+    let slot = ordinal.rem_euclid(BINS_PER_ARRAY as i32) as usize;
+    Ok((index, slot))
+}
+
+/// Id of the bin in `slot` of bin array `index`.
+pub fn bin_id_at(index: i32, slot: usize, bin_step: u16) -> MathResult<i32> {
+    index
+        .checked_mul(BINS_PER_ARRAY as i32)
+        .and_then(|ordinal| ordinal.checked_add(slot as i32))
+        .and_then(|ordinal| ordinal.checked_mul(bin_step as i32))
+        .ok_or(MathError::Overflow)
+}
+
+const POOL_BITMAP_HALF_RANGE: i32 = (BIN_ARRAY_BITMAP_WORDS * 64 / 2) as i32;
+const EXTENSION_BITMAP_RANGE: i32 = (BITMAP_EXTENSION_WORDS * 64) as i32;
+
+/// Where a bin array's liquidity flag lives.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BitmapSlot {
+    Pool(usize),
+    Positive(usize),
+    Negative(usize),
+}
+
+pub fn bitmap_slot(index: i32) -> MathResult<BitmapSlot> {
+    if (-POOL_BITMAP_HALF_RANGE..POOL_BITMAP_HALF_RANGE).contains(&index) {
+        Ok(BitmapSlot::Pool((index + POOL_BITMAP_HALF_RANGE) as usize))
+    } else if (POOL_BITMAP_HALF_RANGE..POOL_BITMAP_HALF_RANGE + EXTENSION_BITMAP_RANGE).contains(&index) {
+        Ok(BitmapSlot::Positive((index - POOL_BITMAP_HALF_RANGE) as usize))
+    } else if (-POOL_BITMAP_HALF_RANGE - EXTENSION_BITMAP_RANGE..-POOL_BITMAP_HALF_RANGE).contains(&index) {
+        Ok(BitmapSlot::Negative((-POOL_BITMAP_HALF_RANGE - 1 - index) as usize))
+    } else {
+        Err(MathError::BinArrayOutOfRange)
+    }
+}
+
+pub fn is_bit_set(words: &[u64], bit: usize) -> bool {
+    words[bit / 64] >> (bit % 64) & 1 == 1
+}
+
+pub fn set_bit(words: &mut [u64], bit: usize, value: bool) {
+    if value {
+        words[bit / 64] |= 1 << (bit % 64);
+    } else {
+        words[bit / 64] &= !(1 << (bit % 64));
+    }
+}
+
+/// First set bit at or past `start`, scanning toward higher bits when `upward`. Whole zero
+/// words are skipped at once.
+fn find_set_bit(words: &[u64], start: usize, upward: bool) -> Option<usize> {
+    if upward {
+        let mut word_index = start / 64;
+        let mut word = *words.get(word_index)? & (u64::MAX << (start % 64));
+        loop {
+            if word != 0 {
+                return Some(word_index * 64 + word.trailing_zeros() as usize);
+            }
+            word_index += 1;
+            word = *words.get(word_index)?;
+        }
+    } else {
+        let start = start.min(words.len().checked_mul(64)?.checked_sub(1)?);
+        let mut word_index = start / 64;
+        let mut word = words[word_index] & (u64::MAX >> (63 - start % 64));
+        loop {
+            if word != 0 {
+                return Some(word_index * 64 + 63 - word.leading_zeros() as usize);
+            }
+            word_index = word_index.checked_sub(1)?;
+            word = words[word_index];
+        }
+    }
+}
+
+/// Which bin arrays of a pool hold liquidity: the pool's own bitmap, plus the positive and
+/// negative halves of its extension when the caller supplied it.
+#[derive(Debug, Clone, Copy)]
+pub struct BinArrayBitmap<'a> {
+    pub pool: &'a [u64],
+    pub extension: Option<(&'a [u64], &'a [u64])>,
+}
+
+impl BinArrayBitmap<'_> {
+    /// Arrays outside the tracked range read as empty. Arrays beyond the pool bitmap cannot
+    /// be judged without the extension, so asking about them without one is an error.
+    pub fn has_liquidity(&self, index: i32) -> MathResult<bool> {
+        match bitmap_slot(index) {
+            Ok(BitmapSlot::Pool(bit)) => Ok(is_bit_set(self.pool, bit)),
+            Ok(BitmapSlot::Positive(bit)) => Ok(is_bit_set(self.extension()?.0, bit)),
+            Ok(BitmapSlot::Negative(bit)) => Ok(is_bit_set(self.extension()?.1, bit)),
+            Err(_) => Ok(false),
+        }
+    }
+
+    fn extension(&self) -> MathResult<(&[u64], &[u64])> {
+        self.extension.ok_or(MathError::MissingBitmapExtension)
+    }
+
+    /// Nearest bin array strictly past `index`, toward higher indices when `upward`, that
+    /// holds liquidity. Fails rather than skip ahead if the scan reaches the extension's
+    /// range and no extension was supplied.
+    pub fn next_with_liquidity(&self, index: i32, upward: bool) -> MathResult<Option<i32>> {
+        let half = POOL_BITMAP_HALF_RANGE;
+        let end = POOL_BITMAP_HALF_RANGE + EXTENSION_BITMAP_RANGE;
+        if upward {
+            let Some(start) = index.checked_add(1).filter(|start| *start < end) else {
+                return Ok(None);
+            };
+            if start < -half {
+                let (_, negative) = self.extension()?;
+                // Negative bits count away from zero, so moving up scans them downward
+                if let Some(bit) = find_set_bit(negative, (-half - 1 - start) as usize, false) {
+                    return Ok(Some(-half - 1 - bit as i32));
+                }
+            }
+            if start < half {
+                if let Some(bit) = find_set_bit(self.pool, (start.max(-half) + half) as usize, true) {
+                    return Ok(Some(bit as i32 - half));
+                }
+            }
+            let (positive, _) = self.extension()?;
+            Ok(find_set_bit(positive, (start.max(half) - half) as usize, true).map(|bit| bit as i32 + half))
+        } else {
+            let Some(start) = index.checked_sub(1).filter(|start| *start >= -end) else {
+                return Ok(None);
+            };
+            if start >= half {
+                let (positive, _) = self.extension()?;
+                if let Some(bit) = find_set_bit(positive, (start - half) as usize, false) {
+                    return Ok(Some(bit as i32 + half));
+                }
+            }
+            if start >= -half {
+                if let Some(bit) = find_set_bit(self.pool, (start.min(half - 1) + half) as usize, false) {
+                    return Ok(Some(bit as i32 - half));
+                }
+            }
+            let (_, negative) = self.extension()?;
+            Ok(find_set_bit(negative, (-half - 1 - start.min(-half - 1)) as usize, true)
+                .map(|bit| -half - 1 - bit as i32))
+        }
+    }
+}
+
+pub fn next_bin_id(bin_id: i32, bin_step: u16, a_to_b: bool) -> MathResult<i32> {
+    if a_to_b {
+        bin_id.checked_sub(bin_step as i32)
+    } else {
+        bin_id.checked_add(bin_step as i32)
+    }
+    .ok_or(MathError::Overflow)
+}
+
+/// Configuration of the volatility-based fee. A zero `variable_fee_control` disables it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DynamicFeeParams {
+    /// Seconds between swaps below which the volatility references are left untouched.
+    pub filter_period: u16,
+    /// Seconds after which past volatility is forgotten entirely.
+    pub decay_period: u16,
+    /// Share of the accumulator, in bps, carried into the reference between the two periods.
+    pub reduction_factor: u16,
+    pub variable_fee_control: u32,
+    pub max_volatility_accumulator: u32,
+}
+
+/// Running volatility measure. The accumulator counts bins moved away from
+/// `index_reference`, in bps of a bin, on top of the decayed `volatility_reference`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct VolatilityState {
+    pub volatility_accumulator: u32,
+    pub volatility_reference: u32,
+    pub index_reference: i32,
+    pub last_update_timestamp: i64,
+}
+
+impl VolatilityState {
+    /// Refreshes the references at the start of a swap from the time since the last one.
+    pub fn update_references(&mut self, params: &DynamicFeeParams, active_bin_id: i32, now: i64) -> MathResult<()> {
+        let elapsed = now.saturating_sub(self.last_update_timestamp);
+        if elapsed >= params.filter_period as i64 {
+            self.index_reference = active_bin_id;
+            self.volatility_reference = if elapsed < params.decay_period as i64 {
+                (self.volatility_accumulator as u128)
+                    .checked_mul(params.reduction_factor as u128)
+                    .ok_or(MathError::Overflow)?
+                    .checked_div(BASIS_POINT_MAX)
+                    .ok_or(MathError::Overflow)? as u32
+            } else {
+                0
+            };
+        }
+        self.last_update_timestamp = now;
+        Ok(())
+    }
+
+    /// Moves the accumulator to `bin_id`, the bin about to be swapped through.
+    pub fn update_accumulator(&mut self, params: &DynamicFeeParams, bin_id: i32, bin_step: u16) -> MathResult<()> {
+        if bin_step == 0 {
+            return Err(MathError::InvalidBinStep);
+        }
+        let bins_moved = (bin_id as i64 - self.index_reference as i64).unsigned_abs() / bin_step as u64;
+        let volatility_accumulator = bins_moved
+            .checked_mul(BASIS_POINT_MAX as u64)
+            .and_then(|delta| delta.checked_add(self.volatility_reference as u64))
+            .ok_or(MathError::Overflow)?;
+        self.volatility_accumulator =
+            volatility_accumulator.min(params.max_volatility_accumulator as u64) as u32;
+        Ok(())
+    }
+}
+
+/// Variable fee in bps for the given accumulator, rounded up.
+pub fn variable_fee_rate(params: &DynamicFeeParams, volatility_accumulator: u32, bin_step: u16) -> MathResult<u128> {
+    let volatility = (volatility_accumulator as u128)
+        .checked_mul(bin_step as u128)
+        .ok_or(MathError::Overflow)?;
+    Ok(volatility
+        .checked_mul(volatility)
+        .and_then(|squared| squared.checked_mul(params.variable_fee_control as u128))
+        .ok_or(MathError::Overflow)?
+        .div_ceil(VARIABLE_FEE_PRECISION))
+}
+
+/// The pool fields a swap depends on. `volatility` must already have its references
+/// refreshed for the current time.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SwapParams {
+    pub active_bin_id: i32,
+    pub bin_step: u16,
+    /// Base fee in bps; the variable fee is added on top per bin.
+    pub fee_rate: u16,
+    pub protocol_fee_bps: u16,
+    pub dynamic_fee: DynamicFeeParams,
+    pub volatility: VolatilityState,
+}
+
+impl SwapParams {
+    /// Parameters for swapping through `bin_id`: the accumulator is moved to that bin and
+    /// the fee becomes base plus variable fee, capped at `MAX_FEE_RATE`.
+    fn at_bin(&self, volatility: &mut VolatilityState, bin_id: i32) -> MathResult<SwapParams> {
+        volatility.update_accumulator(&self.dynamic_fee, bin_id, self.bin_step)?;
+        let variable_fee = variable_fee_rate(&self.dynamic_fee, volatility.volatility_accumulator, self.bin_step)?;
+        let fee_rate = (self.fee_rate as u128)
+            .checked_add(variable_fee)
+            .ok_or(MathError::Overflow)?
+            .min(MAX_FEE_RATE as u128) as u16;
+        Ok(SwapParams { fee_rate, ..*self })
+    }
+}
+
+/// Outcome of walking the bins for a swap. `amount_in` is what the trader actually pays,
+/// which can be less than requested when a limit bin stops execution early.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SwapResult {
+    pub amount_in: u64,
+    pub amount_out: u64,
+    pub fee: u64,
+    pub protocol_fee: u64,
+    pub final_bin_id: i32,
+    /// Bins the swap traded against. Empty bins it skipped over are not counted.
+    pub bins_crossed: u32,
+    /// Volatility state to store on the pool once the swap executes.
+    pub volatility: VolatilityState,
+}
+
+/// What a single bin contributes to a swap, computed without touching the bin.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BinSwapStep {
+    pub amount_in_with_fee: u128,
+    pub amount_out: u128,
+    pub fee: u128,
+    pub protocol_fee: u128,
+    /// LP fee per unit of liquidity, in the input token: added to `fee_growth_per_unit_a` for
+    /// A to B swaps and `fee_growth_per_unit_b` otherwise.
+    pub fee_growth_update: u128,
+    pub amount_a_after: u64,
+    pub amount_b_after: u64,
+}
+
+pub fn swap_step_exact_in(
+    params: &SwapParams,
+    reserves: &BinReserves,
+    bin_id: i32,
+    amount_remaining_in: u128,
+    a_to_b: bool,
+) -> MathResult<Option<BinSwapStep>> {
+    let price = get_price_at_bin(bin_id, params.bin_step)?;
+    let available_amount_out = reserves.amount_out_available(a_to_b) as u128;
+    if available_amount_out == 0 {
+        return Ok(None);
+    }
+
+    // Charge the fee on the whole input rounded up, then price the output the remainder buys
+    // rounded down, so rounding always favours the pool
+    let fee = amount_remaining_in
+        .checked_mul(params.fee_rate as u128)
+        .ok_or(MathError::Overflow)?
+        .div_ceil(BASIS_POINT_MAX);
+    let amount_in_after_fee = amount_remaining_in
+        .checked_sub(fee)
+        .ok_or(MathError::Overflow)?;
+
+    let amount_out = std::cmp::min(
+        convert_amount(amount_in_after_fee, price, a_to_b)?,
+        available_amount_out,
+    );
+    let amount_in_consumed = amount_in_for_out(amount_out, price, a_to_b)?;
+    let amount_in_with_fee = amount_in_with_fee(amount_in_consumed, params.fee_rate)?;
+    if amount_in_with_fee > amount_remaining_in {
+        return Err(MathError::Overflow);
+    }
+
+    bin_swap_step(params, reserves, amount_in_consumed, amount_in_with_fee, amount_out, a_to_b).map(Some)
+}
+
+pub fn swap_step_exact_out(
+    params: &SwapParams,
+    reserves: &BinReserves,
+    bin_id: i32,
+    amount_remaining_out: u128,
+    a_to_b: bool,
+) -> MathResult<Option<BinSwapStep>> {
+    let price = get_price_at_bin(bin_id, params.bin_step)?;
+    if price == 0 {
+        return Err(MathError::Overflow);
+    }
+    let available_amount_out = reserves.amount_out_available(a_to_b) as u128;
+    if available_amount_out == 0 {
+        return Ok(None);
+    }
+
+    let amount_out = std::cmp::min(amount_remaining_out, available_amount_out);
+    let amount_in_consumed = amount_in_for_out(amount_out, price, a_to_b)?;
+    let amount_in_with_fee = amount_in_with_fee(amount_in_consumed, params.fee_rate)?;
+
+    bin_swap_step(params, reserves, amount_in_consumed, amount_in_with_fee, amount_out, a_to_b).map(Some)
+}
+
+/// Input needed to buy `amount_out` at `price`, rounded up so the pool never gives out more
+/// than it is paid for.
+fn amount_in_for_out(amount_out: u128, price: u128, a_to_b: bool) -> MathResult<u128> {
+    if price == 0 {
+        return Err(MathError::Overflow);
+    }
+    if a_to_b {
+        Ok(amount_out
+            .checked_mul(PRECISION)
+            .ok_or(MathError::Overflow)?
+            .div_ceil(price))
+    } else {
+        Ok(amount_out
+            .checked_mul(price)
+            .ok_or(MathError::Overflow)?
+            .div_ceil(PRECISION))
+    }
+}
+
+/// Splits the fee charged in a bin and works out the bin's resulting inventory: the input
+/// net of fees stays in the bin and the output leaves it.
+fn bin_swap_step(
+    params: &SwapParams,
+    reserves: &BinReserves,
+    amount_in_consumed: u128,
+    amount_in_with_fee: u128,
+    amount_out: u128,
+    a_to_b: bool,
+) -> MathResult<BinSwapStep> {
+    let fee = amount_in_with_fee
+        .checked_sub(amount_in_consumed)
+        .ok_or(MathError::Overflow)?;
+    let (protocol_fee, lp_fee) = split_protocol_fee(fee, params.protocol_fee_bps)?;
+    let fee_growth_update = lp_fee
+        .checked_mul(PRECISION)
+        .ok_or(MathError::Overflow)?
+        .checked_div(reserves.liquidity_supply)
+        .ok_or(MathError::Overflow)?;
+    let (amount_in_side, amount_out_side) = if a_to_b {
+        (reserves.amount_a, reserves.amount_b)
+    } else {
+        (reserves.amount_b, reserves.amount_a)
+    };
+    let amount_in_side = u64::try_from(amount_in_consumed)
+        .ok()
+        .and_then(|amount| amount_in_side.checked_add(amount))
+        .ok_or(MathError::Overflow)?;
+    let amount_out_side = u64::try_from(amount_out)
+        .ok()
+        .and_then(|amount| amount_out_side.checked_sub(amount))
+        .ok_or(MathError::Overflow)?;
+    let (amount_a_after, amount_b_after) = if a_to_b {
+        (amount_in_side, amount_out_side)
+    } else {
+        (amount_out_side, amount_in_side)
+    };
+
+    Ok(BinSwapStep {
+        amount_in_with_fee,
+        amount_out,
+        fee,
+        protocol_fee,
+        fee_growth_update,
+        amount_a_after,
+        amount_b_after,
+    })
+}
+
+/// Supplies bins to the swap walkers in the order they are crossed. The program backs this
+/// with bin accounts; off-chain callers can back it with any snapshot of pool state.
+pub trait SwapBins {
+    type Error: From<MathError>;
+
+    /// Inventory of `bin_id`, the next bin on the swap path.
+    fn reserves(&mut self, bin_id: i32) -> Result<BinReserves, Self::Error>;
+
+    /// Called with the step computed for the bin last returned by `reserves`.
+    fn record(&mut self, bin_id: i32, step: &BinSwapStep) -> Result<(), Self::Error>;
+
+    /// The nearest bin past `bin_id` in the swap direction holding the token being bought,
+    /// or `None` once that side of the pool is exhausted. Bins in between are never visited.
+    fn next_bin(&mut self, bin_id: i32, a_to_b: bool) -> Result<Option<i32>, Self::Error>;
+}
+
+/// A bin snapshot keyed by bin id. Bins missing from the snapshot are empty.
+impl SwapBins for BTreeMap<i32, BinReserves> {
+    type Error = MathError;
+
+    fn reserves(&mut self, bin_id: i32) -> MathResult<BinReserves> {
+        Ok(self.get(&bin_id).copied().unwrap_or_default())
+    }
+
+    fn record(&mut self, bin_id: i32, step: &BinSwapStep) -> MathResult<()> {
+        let reserves = self.entry(bin_id).or_default();
+        reserves.amount_a = step.amount_a_after;
+        reserves.amount_b = step.amount_b_after;
+        Ok(())
+    }
+
+    fn next_bin(&mut self, bin_id: i32, a_to_b: bool) -> MathResult<Option<i32>> {
+        let mut bins = if a_to_b {
+            self.range(..bin_id)
+        } else {
+            self.range((Bound::Excluded(bin_id), Bound::Unbounded))
+        }
+        .filter(|(_, reserves)| reserves.amount_out_available(a_to_b) > 0);
+        let next = if a_to_b { bins.next_back() } else { bins.next() };
+        Ok(next.map(|(bin_id, _)| *bin_id))
+    }
+}
+
+/// Walks bins from the active bin spending `amount_in`. With a `limit_bin_id` the walk stops
+/// at the limit or once the pool has no liquidity left in the swap direction, filling what it
+/// can; without one, running out of liquidity is an error.
+pub fn swap_exact_in<B: SwapBins>(
+    params: &SwapParams,
+    amount_in: u64,
+    a_to_b: bool,
+    limit_bin_id: Option<i32>,
+    bins: &mut B,
+) -> Result<SwapResult, B::Error> {
+    let mut amount_remaining_in = amount_in as u128;
+    let mut total_amount_out: u128 = 0;
+    let mut total_fee: u128 = 0;
+    let mut total_protocol_fee: u128 = 0;
+    let mut current_bin_id = params.active_bin_id;
+    let mut bins_crossed: u32 = 0;
+    let mut volatility = params.volatility;
+    let mut last_bin_id = params.active_bin_id;
+
+    while amount_remaining_in > 0 {
+        if let Some(limit) = limit_bin_id {
+            let crossed = if a_to_b { current_bin_id < limit } else { current_bin_id > limit };
+            if crossed {
+                // Stop before crossing the limit; the last bin walked stays active and
+                // the rest of the input is left with the trader
+                current_bin_id = last_bin_id;
+                break;
+            }
+        }
+
+        let reserves = bins.reserves(current_bin_id)?;
+        let bin_params = params.at_bin(&mut volatility, current_bin_id)?;
+        let step = swap_step_exact_in(&bin_params, &reserves, current_bin_id, amount_remaining_in, a_to_b)?;
+        if let Some(step) = step {
+            if step.amount_in_with_fee == 0 {
+                // What is left cannot buy a single unit here; it stays with the trader
+                break;
+            }
+            bins.record(current_bin_id, &step)?;
+            bins_crossed += 1;
+            total_amount_out = total_amount_out
+                .checked_add(step.amount_out)
+                .ok_or(MathError::Overflow)?;
+            total_fee = total_fee.checked_add(step.fee).ok_or(MathError::Overflow)?;
+            total_protocol_fee = total_protocol_fee
+                .checked_add(step.protocol_fee)
+                .ok_or(MathError::Overflow)?;
+            amount_remaining_in = amount_remaining_in
+                .checked_sub(step.amount_in_with_fee)
+                .ok_or(MathError::Overflow)?;
+        }
+
+        last_bin_id = current_bin_id;
+        let bin_exhausted =
+            step.is_none_or(|step| step.amount_out == reserves.amount_out_available(a_to_b) as u128);
+        if !bin_exhausted || limit_bin_id == Some(current_bin_id) {
+            // The input ran out inside this bin, or the next one would cross the limit; the
+            // bin stays active
+            break;
+        }
+        match bins.next_bin(current_bin_id, a_to_b)? {
+            Some(next) => current_bin_id = next,
+            // Only a swap with a limit bin settles for a partial fill
+            None if amount_remaining_in == 0 || limit_bin_id.is_some() => break,
+            None => return Err(MathError::InsufficientLiquidity.into()),
+        }
+    }
+
+    Ok(SwapResult {
+        amount_in: (amount_in as u128)
+            .checked_sub(amount_remaining_in)
+            .ok_or(MathError::Overflow)? as u64,
+        amount_out: total_amount_out as u64,
+        fee: total_fee as u64,
+        protocol_fee: total_protocol_fee as u64,
+        final_bin_id: current_bin_id,
+        bins_crossed,
+        volatility,
+    })
+}
+
+/// Walks bins from the active bin until exactly `amount_out` has been filled.
+pub fn swap_exact_out<B: SwapBins>(
+    params: &SwapParams,
+    amount_out: u64,
+    a_to_b: bool,
+    bins: &mut B,
+) -> Result<SwapResult, B::Error> {
+    let mut amount_remaining_out = amount_out as u128;
+    let mut total_amount_in: u128 = 0;
+    let mut total_fee: u128 = 0;
+    let mut total_protocol_fee: u128 = 0;
+    let mut current_bin_id = params.active_bin_id;
+    let mut bins_crossed: u32 = 0;
+    let mut volatility = params.volatility;
+
+    while amount_remaining_out > 0 {
+        let reserves = bins.reserves(current_bin_id)?;
+        let bin_params = params.at_bin(&mut volatility, current_bin_id)?;
+        let step = swap_step_exact_out(&bin_params, &reserves, current_bin_id, amount_remaining_out, a_to_b)?;
+        if let Some(step) = step {
+            bins.record(current_bin_id, &step)?;
+            bins_crossed += 1;
+            total_amount_in = total_amount_in
+                .checked_add(step.amount_in_with_fee)
+                .ok_or(MathError::Overflow)?;
+            total_fee = total_fee.checked_add(step.fee).ok_or(MathError::Overflow)?;
+            total_protocol_fee = total_protocol_fee
+                .checked_add(step.protocol_fee)
+                .ok_or(MathError::Overflow)?;
+            amount_remaining_out = amount_remaining_out
+                .checked_sub(step.amount_out)
+                .ok_or(MathError::Overflow)?;
+        }
+
+        let bin_exhausted =
+            step.is_none_or(|step| step.amount_out == reserves.amount_out_available(a_to_b) as u128);
+        if !bin_exhausted {
+            // Filled inside this bin, which stays active
+            break;
+        }
+        match bins.next_bin(current_bin_id, a_to_b)? {
+            Some(next) => current_bin_id = next,
+            // The last bin was emptied exactly; it stays active
+            None if amount_remaining_out == 0 => break,
+            None => return Err(MathError::InsufficientLiquidity.into()),
+        }
+    }
+
+    Ok(SwapResult {
+        amount_in: u64::try_from(total_amount_in).map_err(|_| MathError::Overflow)?,
+        amount_out,
+        fee: total_fee as u64,
+        protocol_fee: total_protocol_fee as u64,
+        final_bin_id: current_bin_id,
+        bins_crossed,
+        volatility,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BIN_STEP: u16 = 10;
+
+    fn swap_params(active_bin_id: i32, fee_rate: u16) -> SwapParams {
+        SwapParams { active_bin_id, bin_step: BIN_STEP, fee_rate, ..Default::default() }
+    }
+
+    fn reserves(amount_a: u64, amount_b: u64) -> BinReserves {
+        BinReserves { amount_a, amount_b, liquidity_supply: 1_000_000 }
+    }
+
+    /// `count` bins holding only token B, ending just below `active_bin_id`.
+    fn b_bins_below(active_bin_id: i32, count: i32, amount_b: u64) -> BTreeMap<i32, BinReserves> {
+        (1..=count)
+            .map(|i| (active_bin_id - i * BIN_STEP as i32, reserves(0, amount_b)))
+            .collect()
+    }
+
+    #[test]
+    fn price_at_bin() {
+        assert_eq!(get_price_at_bin(0, BIN_STEP), Ok(PRECISION));
+        assert_eq!(get_price_at_bin(1, BIN_STEP), Ok(PRECISION * 10_010 / 10_000));
+        assert_eq!(get_price_at_bin(10, 0), Err(MathError::InvalidBinStep));
+
+        let mut previous = 0;
+        for bin_id in (-500..=500).step_by(BIN_STEP as usize) {
+            let price = get_price_at_bin(bin_id, BIN_STEP).unwrap();
+            assert!(price > previous, "price must increase with the bin id");
+            previous = price;
+        }
+
+        // Negative bins are the reciprocal of their positive mirror
+        let up = get_price_at_bin(200, BIN_STEP).unwrap();
+        let down = get_price_at_bin(-200, BIN_STEP).unwrap();
+        let product = up * down / PRECISION;
+        assert!(product.abs_diff(PRECISION) <= up / PRECISION + 1);
+    }
+
+    #[test]
+    fn exact_in_step_never_undercharges() {
+        let params = swap_params(200, 0);
+        let bin = reserves(0, 1_000_000);
+        let price = get_price_at_bin(190, BIN_STEP).unwrap();
+        assert!(price > PRECISION);
+
+        // One unit of A at a price above one buys one unit of B and must be charged for it
+        let step = swap_step_exact_in(&params, &bin, 190, 1, true).unwrap().unwrap();
+        assert_eq!(step.amount_out, 1);
+        assert_eq!(step.amount_in_with_fee, 1);
+
+        // With a fee the same unit buys nothing
+        let params = swap_params(200, 25);
+        let step = swap_step_exact_in(&params, &bin, 190, 1, true).unwrap().unwrap();
+        assert_eq!((step.amount_in_with_fee, step.amount_out), (0, 0));
+
+        for amount_in in [2, 7, 999, 12_345, 5_000_000] {
+            for a_to_b in [true, false] {
+                let bin = reserves(1_000_000, 1_000_000);
+                let step = swap_step_exact_in(&params, &bin, 190, amount_in, a_to_b).unwrap().unwrap();
+                assert!(step.amount_in_with_fee <= amount_in);
+                assert!(step.amount_out <= 1_000_000);
+                let consumed = step.amount_in_with_fee - step.fee;
+                // The input kept by the bin is worth at least what it paid out
+                assert!(convert_amount(consumed, price, a_to_b).unwrap() >= step.amount_out);
+                assert!(step.fee * 10_000 >= consumed * 25);
+            }
+        }
+
+        assert_eq!(swap_step_exact_in(&params, &reserves(5, 0), 190, 100, true), Ok(None));
+    }
+
+    #[test]
+    fn exact_out_step_caps_at_available() {
+        let params = swap_params(0, 25);
+        let bin = reserves(1_000, 1_000);
+        let price = get_price_at_bin(-10, BIN_STEP).unwrap();
+
+        let step = swap_step_exact_out(&params, &bin, -10, 400, true).unwrap().unwrap();
+        assert_eq!(step.amount_out, 400);
+        assert_eq!(step.amount_b_after, 600);
+        let consumed = step.amount_in_with_fee - step.fee;
+        assert_eq!(step.amount_a_after as u128, 1_000 + consumed);
+        assert!(convert_amount(consumed, price, true).unwrap() >= 400);
+        assert!(step.fee > 0);
+
+        let step = swap_step_exact_out(&params, &bin, -10, 5_000, false).unwrap().unwrap();
+        assert_eq!(step.amount_out, 1_000);
+        assert_eq!(step.amount_a_after, 0);
+
+        assert_eq!(swap_step_exact_out(&params, &reserves(0, 5), -10, 1, false), Ok(None));
+    }
+
+    #[test]
+    fn exact_in_dust_does_not_walk_the_pool() {
+        let params = swap_params(200, 25);
+        let mut bins = b_bins_below(200, 20, 1_000_000);
+        let result = swap_exact_in(&params, 1, true, None, &mut bins).unwrap();
+        assert_eq!((result.amount_in, result.amount_out), (0, 0));
+        // The empty active bin is skipped, but the swap stops in the first bin with liquidity
+        assert_eq!(result.final_bin_id, 190);
+
+        let mut bins = b_bins_below(200, 20, 1_000_000);
+        let result = swap_exact_in(&params, 1_000, true, None, &mut bins).unwrap();
+        assert_eq!(result.final_bin_id, 190);
+        assert!(result.amount_out > 0);
+        assert!(bins.values().filter(|bin| bin.amount_b < 1_000_000).count() == 1);
+    }
+
+    #[test]
+    fn partially_used_bin_stays_active() {
+        let params = swap_params(0, 25);
+        let mut bins = b_bins_below(10, 3, 1_000_000);
+        let result = swap_exact_in(&params, 10_000, true, None, &mut bins).unwrap();
+        assert_eq!(result.final_bin_id, 0);
+        assert!(bins[&0].amount_b > 0);
+
+        let mut bins = b_bins_below(10, 3, 1_000_000);
+        let result = swap_exact_out(&params, 10_000, true, &mut bins).unwrap();
+        assert_eq!(result.final_bin_id, 0);
+        assert_eq!(bins[&0].amount_b, 990_000);
+
+        // A bin emptied exactly hands over to the next one
+        let mut bins = b_bins_below(10, 3, 1_000_000);
+        let result = swap_exact_out(&params, 1_000_000, true, &mut bins).unwrap();
+        assert_eq!(result.final_bin_id, -10);
+        assert_eq!(result.bins_crossed, 1);
+        assert_eq!(bins[&0].amount_b, 0);
+
+        // Once the last bin is emptied it stays active
+        let mut bins = b_bins_below(10, 3, 1_000_000);
+        let result = swap_exact_out(&params, 3_000_000, true, &mut bins).unwrap();
+        assert_eq!(result.final_bin_id, -20);
+        assert_eq!(
+            swap_exact_out(&params, 3_000_001, true, &mut b_bins_below(10, 3, 1_000_000)),
+            Err(MathError::InsufficientLiquidity)
+        );
+    }
+
+    #[test]
+    fn exact_in_fills_partially_only_with_a_limit() {
+        let params = swap_params(0, 25);
+        assert_eq!(
+            swap_exact_in(&params, 10_000_000, true, None, &mut b_bins_below(10, 3, 1_000_000)),
+            Err(MathError::InsufficientLiquidity)
+        );
+
+        let mut bins = b_bins_below(10, 3, 1_000_000);
+        let result = swap_exact_in(&params, 10_000_000, true, Some(-1_000), &mut bins).unwrap();
+        assert_eq!(result.amount_out, 3_000_000);
+        assert!(result.amount_in < 10_000_000);
+        assert_eq!(result.final_bin_id, -20);
+    }
+
+    #[test]
+    fn sweeping_swap_converts_a_single_bin_order() {
+        // A sell-A order alone in the bin above the active one, with more liquidity past it
+        let params = swap_params(0, 25);
+        let pool = || -> BTreeMap<i32, BinReserves> {
+            [(10, reserves(50_000, 0)), (20, reserves(1_000_000, 0))].into_iter().collect()
+        };
+
+        let mut bins = pool();
+        let result = swap_exact_in(&params, 60_000, false, None, &mut bins).unwrap();
+        assert_eq!(result.final_bin_id, 20);
+        // The empty active bin is skipped, not traded against
+        assert_eq!(result.bins_crossed, 2);
+        assert_eq!(bins[&10].amount_a, 0);
+        assert!(bins[&10].amount_b > 50_000);
+
+        // Stopped at the order's bin by a limit, the bin is still fully converted
+        let mut bins = pool();
+        let result = swap_exact_in(&params, 60_000, false, Some(10), &mut bins).unwrap();
+        assert_eq!(result.final_bin_id, 10);
+        assert_eq!(bins[&10].amount_a, 0);
+        assert_eq!(bins[&20].amount_a, 1_000_000);
+    }
+
+    #[test]
+    fn exact_in_and_exact_out_round_trip() {
+        let params = swap_params(0, 30);
+        let pool = || -> BTreeMap<i32, BinReserves> {
+            (-5..=5)
+                .map(|i| {
+                    let bin_id = i * BIN_STEP as i32;
+                    let bin = match i.cmp(&0) {
+                        std::cmp::Ordering::Less => reserves(0, 250_000),
+                        std::cmp::Ordering::Equal => reserves(100_000, 100_000),
+                        std::cmp::Ordering::Greater => reserves(250_000, 0),
+                    };
+                    (bin_id, bin)
+                })
+                .collect()
+        };
+
+        for a_to_b in [true, false] {
+            for amount in [1_000, 100_000, 600_000] {
+                let exact_in = swap_exact_in(&params, amount, a_to_b, None, &mut pool()).unwrap();
+                assert_eq!(exact_in.amount_in, amount);
+                // Buying the same output back costs no more than was paid for it
+                let exact_out = swap_exact_out(&params, exact_in.amount_out, a_to_b, &mut pool()).unwrap();
+                assert!(exact_out.amount_in <= amount);
+
+                // Paying what an exact-out swap charged buys at least that output
+                let exact_out = swap_exact_out(&params, amount, a_to_b, &mut pool()).unwrap();
+                let exact_in = swap_exact_in(&params, exact_out.amount_in, a_to_b, None, &mut pool()).unwrap();
+                assert!(exact_in.amount_out >= amount);
+            }
+        }
+
+        // A limit bin stops the walk and leaves the rest of the input with the trader
+        let result = swap_exact_in(&params, 1_000_000, true, Some(-20), &mut pool()).unwrap();
+        assert_eq!(result.final_bin_id, -20);
+        assert!(result.amount_in < 1_000_000);
+        assert_eq!(result.amount_out, 100_000 + 250_000 * 2);
+    }
+
+    fn dynamic_fee() -> DynamicFeeParams {
+        DynamicFeeParams {
+            filter_period: 30,
+            decay_period: 600,
+            reduction_factor: 5_000,
+            variable_fee_control: 40_000,
+            max_volatility_accumulator: 350_000,
+        }
+    }
+
+    #[test]
+    fn volatility_references_follow_the_filter_and_decay_windows() {
+        let params = dynamic_fee();
+        let state = VolatilityState {
+            volatility_accumulator: 80_000,
+            volatility_reference: 20_000,
+            index_reference: 100,
+            last_update_timestamp: 1_000,
+        };
+
+        // Inside the filter period the references are left alone
+        let mut filtered = state;
+        filtered.update_references(&params, 150, 1_029).unwrap();
+        assert_eq!((filtered.index_reference, filtered.volatility_reference), (100, 20_000));
+        assert_eq!(filtered.last_update_timestamp, 1_029);
+
+        // Between the two periods the reference keeps the reduced accumulator
+        let mut reduced = state;
+        reduced.update_references(&params, 150, 1_030).unwrap();
+        assert_eq!((reduced.index_reference, reduced.volatility_reference), (150, 40_000));
+        let mut reduced = state;
+        reduced.update_references(&params, 150, 1_599).unwrap();
+        assert_eq!(reduced.volatility_reference, 40_000);
+
+        // From the decay period on, past volatility is forgotten
+        let mut decayed = state;
+        decayed.update_references(&params, 150, 1_600).unwrap();
+        assert_eq!((decayed.index_reference, decayed.volatility_reference), (150, 0));
+    }
+
+    #[test]
+    fn volatility_accumulator_counts_bins_from_the_reference() {
+        let params = dynamic_fee();
+        let mut state = VolatilityState { volatility_reference: 20_000, index_reference: 100, ..Default::default() };
+        state.update_accumulator(&params, 130, BIN_STEP).unwrap();
+        assert_eq!(state.volatility_accumulator, 3 * 10_000 + 20_000);
+        state.update_accumulator(&params, 70, BIN_STEP).unwrap();
+        assert_eq!(state.volatility_accumulator, 3 * 10_000 + 20_000);
+
+        // Clamped at the configured maximum
+        state.update_accumulator(&params, 100 + 1_000 * BIN_STEP as i32, BIN_STEP).unwrap();
+        assert_eq!(state.volatility_accumulator, params.max_volatility_accumulator);
+        assert_eq!(state.update_accumulator(&params, 100, 0), Err(MathError::InvalidBinStep));
+    }
+
+    #[test]
+    fn variable_fee_grows_with_the_square_of_volatility() {
+        let params = dynamic_fee();
+        assert_eq!(variable_fee_rate(&params, 0, 100), Ok(0));
+        // (100_000 * 100)^2 * 40_000 / 1e16 = 400 bps
+        assert_eq!(variable_fee_rate(&params, 100_000, 100), Ok(400));
+        assert_eq!(variable_fee_rate(&params, 200_000, 100), Ok(1_600));
+        // Any non-zero variable fee rounds up to at least one bps
+        assert_eq!(variable_fee_rate(&params, 1, 1), Ok(1));
+        let disabled = DynamicFeeParams { variable_fee_control: 0, ..params };
+        assert_eq!(variable_fee_rate(&disabled, 350_000, 100), Ok(0));
+
+        // Base plus variable fee is capped per bin
+        let swap = SwapParams { bin_step: 100, fee_rate: 100, dynamic_fee: params, ..Default::default() };
+        let mut volatility = VolatilityState::default();
+        assert_eq!(swap.at_bin(&mut volatility, 100 * 100).unwrap().fee_rate, MAX_FEE_RATE);
+        assert_eq!(volatility.volatility_accumulator, params.max_volatility_accumulator);
+        assert_eq!(swap.at_bin(&mut volatility, 100).unwrap().fee_rate, 100 + 4);
+    }
+
+    #[test]
+    fn scaled_liquidity_fits_the_amounts() {
+        let active_bin_id = 0;
+        let bin_ids = [-20, -10, 0, 10, 20];
+        let reference_liquidity = [PRECISION, 2 * PRECISION, 3 * PRECISION, 2 * PRECISION, PRECISION];
+        let reference_amounts: Vec<(u128, u128)> = bin_ids
+            .iter()
+            .zip(reference_liquidity)
+            .map(|(bin_id, liquidity)| {
+                calculate_required_for_bin(active_bin_id, *bin_id, BIN_STEP, &BinReserves::default(), liquidity)
+                    .unwrap()
+            })
+            .collect();
+
+        for (amount_a, amount_b) in [(1_000_000, 1_000_000), (10, 1_000_000), (1_000_000, 10), (7, 3)] {
+            let liquidities =
+                scale_liquidity_to_amounts(&reference_liquidity, &reference_amounts, amount_a, amount_b).unwrap();
+            let (mut total_a, mut total_b) = (0, 0);
+            for (bin_id, liquidity) in bin_ids.iter().zip(&liquidities) {
+                let (a, b) =
+                    calculate_required_for_bin(active_bin_id, *bin_id, BIN_STEP, &BinReserves::default(), *liquidity)
+                        .unwrap();
+                total_a += a;
+                total_b += b;
+            }
+            assert!(total_a <= amount_a as u128 && total_b <= amount_b as u128);
+            // Every bin keeps the reference shape
+            assert!(liquidities[0] <= liquidities[1] && liquidities[1] <= liquidities[2]);
+        }
+
+        let liquidities =
+            scale_liquidity_to_amounts(&reference_liquidity, &reference_amounts, 1_000_000, 1_000_000).unwrap();
+        assert!(liquidities[2] > 0);
+        assert_eq!(
+            scale_liquidity_to_amounts(&[PRECISION], &[(0, 0)], 100, 100),
+            Ok(vec![0])
+        );
+    }
+
+    fn set_array(pool: &mut [u64], positive: &mut [u64], negative: &mut [u64], index: i32) {
+        match bitmap_slot(index).unwrap() {
+            BitmapSlot::Pool(bit) => set_bit(pool, bit, true),
+            BitmapSlot::Positive(bit) => set_bit(positive, bit, true),
+            BitmapSlot::Negative(bit) => set_bit(negative, bit, true),
+        }
+    }
+
+    #[test]
+    fn bitmap_scan() {
+        let words = [0, 1 << 3, 0, 1 << 63];
+        assert_eq!(find_set_bit(&words, 0, true), Some(67));
+        assert_eq!(find_set_bit(&words, 68, true), Some(255));
+        assert_eq!(find_set_bit(&words, 255, false), Some(255));
+        assert_eq!(find_set_bit(&words, 254, false), Some(67));
+        assert_eq!(find_set_bit(&words, 66, false), None);
+        assert_eq!(find_set_bit(&words, 1_000, false), Some(255));
+
+        let mut pool = [0u64; BIN_ARRAY_BITMAP_WORDS];
+        let mut positive = [0u64; BITMAP_EXTENSION_WORDS];
+        let mut negative = [0u64; BITMAP_EXTENSION_WORDS];
+        for index in [-600, -3, 5, 700] {
+            set_array(&mut pool, &mut positive, &mut negative, index);
+        }
+        assert_eq!(bitmap_slot(1_000_000), Err(MathError::BinArrayOutOfRange));
+
+        let without_extension = BinArrayBitmap { pool: &pool, extension: None };
+        assert_eq!(without_extension.next_with_liquidity(0, true), Ok(Some(5)));
+        assert_eq!(without_extension.next_with_liquidity(0, false), Ok(Some(-3)));
+        // Past the pool bitmap the scan can't tell whether it would skip liquidity
+        assert_eq!(without_extension.next_with_liquidity(5, true), Err(MathError::MissingBitmapExtension));
+        assert_eq!(without_extension.next_with_liquidity(-3, false), Err(MathError::MissingBitmapExtension));
+        assert_eq!(without_extension.has_liquidity(700), Err(MathError::MissingBitmapExtension));
+        assert_eq!(without_extension.has_liquidity(1_000_000), Ok(false));
+
+        let bitmap = BinArrayBitmap { pool: &pool, extension: Some((&positive, &negative)) };
+        assert_eq!(bitmap.has_liquidity(700), Ok(true));
+        assert_eq!(bitmap.has_liquidity(-600), Ok(true));
+        assert_eq!(bitmap.has_liquidity(6), Ok(false));
+        assert_eq!(bitmap.next_with_liquidity(5, true), Ok(Some(700)));
+        assert_eq!(bitmap.next_with_liquidity(700, true), Ok(None));
+        assert_eq!(bitmap.next_with_liquidity(-3, false), Ok(Some(-600)));
+        assert_eq!(bitmap.next_with_liquidity(-600, false), Ok(None));
+        assert_eq!(bitmap.next_with_liquidity(-1_000, true), Ok(Some(-600)));
+        assert_eq!(bitmap.next_with_liquidity(1_000, false), Ok(Some(700)));
+    }
+}
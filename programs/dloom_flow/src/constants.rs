@@ -1,7 +1,35 @@
 pub const BASIS_POINT_MAX: u128 = 10000;
 pub const PRECISION: u128 = 1_000_000_000_000;
-pub const MAX_BINS_PER_POSITION: i32 = 500;
-pub const ALLOWED_PARAMETERS: &[(u16, u16)] = &[
+// Positions past `Position::BINS_PER_ALLOCATION` bins are grown over several instructions.
+pub const MAX_BINS_PER_POSITION: usize = 500;
+pub const BINS_PER_ARRAY: usize = 70;
+// The pool bitmap flags the bin arrays centred on index 0; the extension covers as many
+// words' worth again on each side beyond that.
+pub const BIN_ARRAY_BITMAP_WORDS: usize = 16;
+pub const BITMAP_EXTENSION_WORDS: usize = 128;
+pub const MAX_FEE_TIERS: usize = 32;
+// Keeps the deserialized observation buffer well inside the program heap.
+pub const MAX_ORACLE_LENGTH: usize = 512;
+pub const MAX_PROTOCOL_FEE_BPS: u16 = 5000;
+pub const POSITION_NFT_NAME: &str = "DLMM Position";
+pub const POSITION_NFT_SYMBOL: &str = "DLMMLP";
+// Token-2022 position NFTs point at this base followed by the position address.
+pub const POSITION_NFT_URI_BASE: &str = "https://dloom.flow/positions/";
+pub const NUM_REWARDS: usize = 2;
+// `Position::operator_permissions` bits.
+pub const PERMISSION_CLAIM_FEES: u8 = 1 << 0;
+pub const PERMISSION_ADD_LIQUIDITY: u8 = 1 << 1;
+pub const PERMISSION_MODIFY_LIQUIDITY: u8 = 1 << 2;
+pub const ALL_PERMISSIONS: u8 = PERMISSION_CLAIM_FEES | PERMISSION_ADD_LIQUIDITY | PERMISSION_MODIFY_LIQUIDITY;
+// `Position::limit_order` values.
+pub const LIMIT_ORDER_NONE: u8 = 0;
+pub const LIMIT_ORDER_SELL_A: u8 = 1;
+pub const LIMIT_ORDER_SELL_B: u8 = 2;
+// Cap on base plus variable fee, in bps.
+pub const MAX_FEE_RATE: u16 = 1000;
+// Scales `variable_fee_control * (volatility_accumulator * bin_step)^2` down to bps.
+pub const VARIABLE_FEE_PRECISION: u128 = 10_000_000_000_000_000;
+pub const DEFAULT_FEE_TIERS: &[(u16, u16)] = &[
     (1, 10),
     (5, 10),
     (20, 10),
@@ -2,6 +2,8 @@
 
 use anchor_lang::prelude::*;
 
+use crate::bin_math::MathError;
+
 #[error_code]
 pub enum MyError {
     #[msg("The provided fee and bin_step parameters are not on the whitelist.")]
@@ -42,4 +44,77 @@ pub enum MyError {
     InvalidBinAccount,
     #[msg("The provided position account does not belong to the specified pool.")]
     InvalidPool,
+    #[msg("The signer is not the admin of the global config.")]
+    NotAdmin,
+    #[msg("This fee tier is already registered.")]
+    FeeTierAlreadyExists,
+    #[msg("This fee tier is not registered.")]
+    FeeTierNotFound,
+    #[msg("The global config cannot hold any more fee tiers.")]
+    FeeTierLimitReached,
+    #[msg("The protocol fee share exceeds the allowed maximum.")]
+    InvalidProtocolFee,
+    #[msg("A bin array covering one of the required bins was not provided.")]
+    MissingBinArray,
+    #[msg("The provided bin array does not belong to this pool.")]
+    InvalidBinArray,
+    #[msg("The bin array lies outside the range tracked by the liquidity bitmap.")]
+    BinArrayOutOfRange,
+    #[msg("The bin array bitmap extension is required for bins this far from the origin.")]
+    MissingBitmapExtension,
+    #[msg("The dynamic fee parameters are invalid.")]
+    InvalidDynamicFeeParameters,
+    #[msg("The oracle cannot grow beyond its maximum length.")]
+    OracleLengthExceeded,
+    #[msg("The oracle has no observation old enough for the requested window.")]
+    OracleObservationTooOld,
+    #[msg("The TWAP window must be greater than zero.")]
+    InvalidTwapWindow,
+    #[msg("Bin weights must target distinct bins and sum to 10000 bps.")]
+    InvalidWeightDistribution,
+    #[msg("The deposit requires more tokens than the maximum you allowed.")]
+    DepositAmountExceeded,
+    #[msg("The active bin moved further from the expected bin than you allowed.")]
+    ActiveBinSlippageExceeded,
+    #[msg("A single-sided deposit must lie entirely on its token's side of the active bin.")]
+    InvalidOneSidedRange,
+    #[msg("Limit order positions can only be filled, claimed or cancelled.")]
+    LimitOrderPosition,
+    #[msg("The position is not a limit order.")]
+    NotLimitOrder,
+    #[msg("The limit order's bin still holds the token it sells.")]
+    LimitOrderNotFilled,
+    #[msg("The pool already has the maximum number of rewards.")]
+    RewardLimitReached,
+    #[msg("The reward index does not refer to an initialized reward.")]
+    InvalidRewardIndex,
+    #[msg("The reward duration must be greater than zero.")]
+    InvalidRewardDuration,
+    #[msg("The operator permissions contain unknown bits.")]
+    InvalidOperatorPermissions,
+    #[msg("The fee rate exceeds the allowed maximum.")]
+    InvalidFeeRate,
+    #[msg("Burning a Metaplex position NFT needs its metadata, master edition and the metadata program.")]
+    MissingMetadataAccounts,
+    #[msg("The position account does not cover its whole range yet; grow it with increase_position_length.")]
+    PositionNotAllocated,
+}
+
+impl From<MathError> for MyError {
+    fn from(error: MathError) -> Self {
+        match error {
+            MathError::Overflow => MyError::MathOverflow,
+            MathError::InvalidBinStep => MyError::InvalidBinStep,
+            MathError::InvalidBinId => MyError::InvalidBinId,
+            MathError::InsufficientLiquidity => MyError::InsufficientLiquidityForSwap,
+            MathError::BinArrayOutOfRange => MyError::BinArrayOutOfRange,
+            MathError::MissingBitmapExtension => MyError::MissingBitmapExtension,
+        }
+    }
+}
+
+impl From<MathError> for Error {
+    fn from(error: MathError) -> Self {
+        MyError::from(error).into()
+    }
 }
\ No newline at end of file
@@ -1,16 +1,25 @@
 // FILE: instructions.rs
 
-use anchor_lang::prelude::*;
+use anchor_lang::{
+    prelude::*,
+    system_program::{self, Transfer},
+};
 use anchor_spl::{
     associated_token::AssociatedToken,
+    token_2022::Token2022,
     token_interface::{
-        self, Burn, CloseAccount, Mint, MintTo, TokenAccount, TokenInterface, TransferChecked,
+        self,
+        spl_pod::optional_keys::OptionalNonZeroPubkey,
+        spl_token_metadata_interface::state::{Field, TokenMetadata},
+        spl_token_2022::instruction::AuthorityType,
+        Burn, CloseAccount, Mint, MintTo, SetAuthority, TokenAccount, TokenInterface,
+        TokenMetadataInitialize, TokenMetadataUpdateField, TransferChecked,
     },
 };
 use mpl_token_metadata::{
     accounts::{MasterEdition, Metadata},
     instructions::{
-        CreateMasterEditionV3Cpi, CreateMasterEditionV3CpiAccounts,
+        BurnNftCpi, BurnNftCpiAccounts, CreateMasterEditionV3Cpi, CreateMasterEditionV3CpiAccounts,
         CreateMasterEditionV3InstructionArgs, CreateMetadataAccountV3Cpi,
         CreateMetadataAccountV3CpiAccounts, CreateMetadataAccountV3InstructionArgs,
     },
@@ -34,9 +43,11 @@ pub mod initialize_pool {
         initial_bin_id: i32,
     ) -> Result<()> {
         require!(
-            is_allowed_parameter(bin_step, fee_rate),
+            is_allowed_parameter(&ctx.accounts.global_config, bin_step, fee_rate),
             MyError::InvalidParameters
         );
+        // Bins live on multiples of the bin step, so the active bin must too
+        require!(initial_bin_id % bin_step as i32 == 0, MyError::InvalidBinId);
         let pool = &mut ctx.accounts.pool;
         pool.bump = ctx.bumps.pool;
         pool.bin_step = bin_step;
@@ -48,29 +59,195 @@ pub mod initialize_pool {
         pool.active_bin_id = initial_bin_id;
         pool.reserves_a = 0;
         pool.reserves_b = 0;
+        pool.bin_array_bitmap = [0; BIN_ARRAY_BITMAP_WORDS];
+        pool.protocol_fee_bps = 0;
+        pool.protocol_fees_a = 0;
+        pool.protocol_fees_b = 0;
+        pool.filter_period = 0;
+        pool.decay_period = 0;
+        pool.reduction_factor = 0;
+        pool.variable_fee_control = 0;
+        pool.max_volatility_accumulator = 0;
+        pool.volatility_accumulator = 0;
+        pool.volatility_reference = 0;
+        pool.index_reference = initial_bin_id;
+        pool.last_update_timestamp = 0;
+
+        let oracle = &mut ctx.accounts.oracle;
+        oracle.bump = ctx.bumps.oracle;
+        oracle.pool = pool.key();
+        oracle.index = 0;
+        oracle.length = 1;
+        oracle.observations = vec![Observation {
+            timestamp: Clock::get()?.unix_timestamp,
+            cumulative_active_bin_id: 0,
+        }];
+
+        emit!(PoolCreated {
+            pool: pool.key(),
+            token_a_mint: pool.token_a_mint,
+            token_b_mint: pool.token_b_mint,
+            bin_step,
+            fee_rate,
+            active_bin_id: initial_bin_id,
+        });
+        Ok(())
+    }
+    fn is_allowed_parameter(config: &GlobalConfig, bin_step: u16, fee_rate: u16) -> bool {
+        config
+            .fee_tiers
+            .iter()
+            .any(|tier| tier.bin_step == bin_step && tier.fee_rate == fee_rate)
+    }
+}
+pub mod initialize_global_config {
+    use super::*;
+    pub fn handler(ctx: Context<InitializeGlobalConfig>) -> Result<()> {
+        let config = &mut ctx.accounts.global_config;
+        config.bump = ctx.bumps.global_config;
+        config.admin = ctx.accounts.authority.key();
+        // Seed the config with the tiers that used to be hard-coded so existing pairs keep working.
+        config.fee_tiers = DEFAULT_FEE_TIERS
+            .iter()
+            .map(|&(bin_step, fee_rate)| FeeTier { bin_step, fee_rate })
+            .collect();
+        Ok(())
+    }
+}
+pub mod add_fee_tier {
+    use super::*;
+    pub fn handler(ctx: Context<UpdateGlobalConfig>, bin_step: u16, fee_rate: u16) -> Result<()> {
+        require!(bin_step > 0, MyError::InvalidBinStep);
+        // Swaps cap the total fee at `MAX_FEE_RATE`, so a higher base fee could never be charged
+        require!(fee_rate <= MAX_FEE_RATE, MyError::InvalidFeeRate);
+        let config = &mut ctx.accounts.global_config;
+        let tier = FeeTier { bin_step, fee_rate };
+        require!(!config.fee_tiers.contains(&tier), MyError::FeeTierAlreadyExists);
+        require!(config.fee_tiers.len() < MAX_FEE_TIERS, MyError::FeeTierLimitReached);
+        config.fee_tiers.push(tier);
         Ok(())
     }
-    fn is_allowed_parameter(bin_step: u16, fee_rate: u16) -> bool {
-        ALLOWED_PARAMETERS
+}
+pub mod remove_fee_tier {
+    use super::*;
+    pub fn handler(ctx: Context<UpdateGlobalConfig>, bin_step: u16, fee_rate: u16) -> Result<()> {
+        let config = &mut ctx.accounts.global_config;
+        let index = config
+            .fee_tiers
             .iter()
-            .any(|&(bs, fr)| bs == bin_step && fr == fee_rate)
+            .position(|tier| tier.bin_step == bin_step && tier.fee_rate == fee_rate)
+            .ok_or(MyError::FeeTierNotFound)?;
+        config.fee_tiers.remove(index);
+        Ok(())
+    }
+}
+pub mod set_admin {
+    use super::*;
+    pub fn handler(ctx: Context<UpdateGlobalConfig>, new_admin: Pubkey) -> Result<()> {
+        ctx.accounts.global_config.admin = new_admin;
+        Ok(())
+    }
+}
+pub mod set_protocol_fee {
+    use super::*;
+    pub fn handler(ctx: Context<UpdatePoolConfig>, protocol_fee_bps: u16) -> Result<()> {
+        require!(protocol_fee_bps <= MAX_PROTOCOL_FEE_BPS, MyError::InvalidProtocolFee);
+        ctx.accounts.pool.protocol_fee_bps = protocol_fee_bps;
+        Ok(())
+    }
+}
+pub mod set_dynamic_fee_parameters {
+    use super::*;
+    pub fn handler(
+        ctx: Context<UpdatePoolConfig>,
+        filter_period: u16,
+        decay_period: u16,
+        reduction_factor: u16,
+        variable_fee_control: u32,
+        max_volatility_accumulator: u32,
+    ) -> Result<()> {
+        require!(
+            filter_period <= decay_period && reduction_factor as u128 <= BASIS_POINT_MAX,
+            MyError::InvalidDynamicFeeParameters
+        );
+        let pool = &mut ctx.accounts.pool;
+        pool.filter_period = filter_period;
+        pool.decay_period = decay_period;
+        pool.reduction_factor = reduction_factor;
+        pool.variable_fee_control = variable_fee_control;
+        pool.max_volatility_accumulator = max_volatility_accumulator;
+        // Start measuring afresh under the new parameters
+        pool.volatility_accumulator = 0;
+        pool.volatility_reference = 0;
+        pool.index_reference = pool.active_bin_id;
+        Ok(())
+    }
+}
+pub mod collect_protocol_fees {
+    use super::*;
+    pub fn handler(ctx: Context<CollectProtocolFees>) -> Result<()> {
+        let pool = &mut ctx.accounts.pool;
+        let amount_a = pool.protocol_fees_a;
+        let amount_b = pool.protocol_fees_b;
+        pool.protocol_fees_a = 0;
+        pool.protocol_fees_b = 0;
+        let seeds = &[b"pool", pool.token_a_mint.as_ref(), pool.token_b_mint.as_ref(), &pool.bin_step.to_le_bytes(), &[pool.bump]];
+        let signer_seeds = &[&seeds[..]];
+        if amount_a > 0 {
+            token_interface::transfer_checked(CpiContext::new_with_signer(ctx.accounts.token_a_program.to_account_info(), TransferChecked { from: ctx.accounts.token_a_vault.to_account_info(), to: ctx.accounts.treasury_token_a_account.to_account_info(), authority: pool.to_account_info(), mint: ctx.accounts.token_a_mint.to_account_info() }, signer_seeds), amount_a, ctx.accounts.token_a_mint.decimals)?;
+        }
+        if amount_b > 0 {
+            token_interface::transfer_checked(CpiContext::new_with_signer(ctx.accounts.token_b_program.to_account_info(), TransferChecked { from: ctx.accounts.token_b_vault.to_account_info(), to: ctx.accounts.treasury_token_b_account.to_account_info(), authority: pool.to_account_info(), mint: ctx.accounts.token_b_mint.to_account_info() }, signer_seeds), amount_b, ctx.accounts.token_b_mint.decimals)?;
+        }
+        Ok(())
     }
 }
 pub mod get_price {
     use super::*;
     pub fn handler(ctx: Context<GetPrice>, bin_id: i32) -> Result<u128> {
         let pool = &ctx.accounts.pool;
-        math::get_price_at_bin(bin_id, pool.bin_step)
+        Ok(math::get_price_at_bin(bin_id, pool.bin_step)?)
+    }
+}
+pub mod increase_oracle_length {
+    use super::*;
+    pub fn handler(ctx: Context<IncreaseOracleLength>, length_to_add: u16) -> Result<()> {
+        math::grow_oracle(&mut ctx.accounts.oracle, length_to_add)
+    }
+}
+pub mod get_twap {
+    use super::*;
+    pub fn handler(ctx: Context<GetTwap>, seconds_ago: u32) -> Result<i32> {
+        require!(seconds_ago > 0, MyError::InvalidTwapWindow);
+        let pool = &ctx.accounts.pool;
+        let oracle = &ctx.accounts.oracle;
+        let now = Clock::get()?.unix_timestamp;
+        let cumulative_now = math::cumulative_active_bin_id_at(oracle, pool.active_bin_id, now)?;
+        let cumulative_then = math::cumulative_active_bin_id_at(oracle, pool.active_bin_id, now - seconds_ago as i64)?;
+        let twap_bin_id = (cumulative_now - cumulative_then).div_euclid(seconds_ago as i64);
+        Ok(i32::try_from(twap_bin_id).map_err(|_| MyError::MathOverflow)?)
+    }
+}
+pub mod initialize_bin_array {
+    use super::*;
+    pub fn handler(ctx: Context<InitializeBinArray>, index: i32) -> Result<()> {
+        let mut bin_array = ctx.accounts.bin_array.load_init()?;
+        bin_array.pool = ctx.accounts.pool.key();
+        bin_array.index = index;
+
+        emit!(BinArrayInitialized {
+            pool: bin_array.pool,
+            bin_array: ctx.accounts.bin_array.key(),
+            index,
+        });
+        Ok(())
     }
 }
-pub mod initialize_bin {
+pub mod initialize_bitmap_extension {
     use super::*;
-    pub fn handler(ctx: Context<InitializeBin>, _bin_id: i32) -> Result<()> {
-        let bin_loader = &mut ctx.accounts.bin;
-        let mut bin = bin_loader.load_init()?;
-        bin.liquidity = 0;
-        bin.fee_growth_per_unit_a = 0;
-        bin.fee_growth_per_unit_b = 0;
+    pub fn handler(ctx: Context<InitializeBitmapExtension>) -> Result<()> {
+        let mut bitmap_extension = ctx.accounts.bitmap_extension.load_init()?;
+        bitmap_extension.pool = ctx.accounts.pool.key();
         Ok(())
     }
 }
@@ -83,31 +260,29 @@ pub mod open_position {
         lower_bin_id: i32,
         upper_bin_id: i32,
     ) -> Result<()> {
-        require!(lower_bin_id < upper_bin_id, MyError::InvalidBinRange);
-        let bin_step = ctx.accounts.pool.bin_step as i32;
-        require!(
-            lower_bin_id % bin_step == 0 && upper_bin_id % bin_step == 0,
-            MyError::InvalidBinId
-        );
-
-        let position = &mut ctx.accounts.position;
-        position.pool = ctx.accounts.pool.key();
-        position.owner = ctx.accounts.owner.key();
-        position.lower_bin_id = lower_bin_id;
-        position.upper_bin_id = upper_bin_id;
-        position.liquidity = 0;
-        position.position_mint = ctx.accounts.position_mint.key();
-        position.fee_growth_snapshot_a = 0;
-        position.fee_growth_snapshot_b = 0;
+        init_position(
+            &ctx.accounts.pool,
+            &ctx.accounts.position,
+            ctx.accounts.owner.key(),
+            ctx.accounts.position_mint.key(),
+            lower_bin_id,
+            upper_bin_id,
+        )?;
 
+        // The pool PDA is mint, freeze and update authority, so holders cannot mint more
+        let pool = &ctx.accounts.pool;
+        let pool_info = pool.to_account_info();
+        let seeds = &[b"pool", pool.token_a_mint.as_ref(), pool.token_b_mint.as_ref(), &pool.bin_step.to_le_bytes(), &[pool.bump]];
+        let signer_seeds = &[&seeds[..]];
         token_interface::mint_to(
-            CpiContext::new(
+            CpiContext::new_with_signer(
                 ctx.accounts.token_program.to_account_info(),
                 MintTo {
                     mint: ctx.accounts.position_mint.to_account_info(),
                     to: ctx.accounts.user_position_nft_account.to_account_info(),
-                    authority: ctx.accounts.owner.to_account_info(),
+                    authority: pool_info.clone(),
                 },
+                signer_seeds,
             ),
             1,
         )?;
@@ -117,16 +292,16 @@ pub mod open_position {
             CreateMetadataAccountV3CpiAccounts {
                 metadata: &ctx.accounts.metadata_account,
                 mint: &ctx.accounts.position_mint.to_account_info(),
-                mint_authority: &ctx.accounts.owner.to_account_info(),
+                mint_authority: &pool_info,
                 payer: &ctx.accounts.owner.to_account_info(),
-                update_authority: (&ctx.accounts.owner.to_account_info(), true),
+                update_authority: (&pool_info, true),
                 system_program: &ctx.accounts.system_program,
                 rent: Some(&ctx.accounts.rent.to_account_info()),
             },
             CreateMetadataAccountV3InstructionArgs {
                 data: DataV2 {
-                    name: "DLMM Position".to_string(),
-                    symbol: "DLMMLP".to_string(),
+                    name: POSITION_NFT_NAME.to_string(),
+                    symbol: POSITION_NFT_SYMBOL.to_string(),
                     uri: "".to_string(),
                     seller_fee_basis_points: 0,
                     creators: None,
@@ -137,131 +312,461 @@ pub mod open_position {
                 collection_details: None,
             },
         )
-        .invoke()?;
+        .invoke_signed(signer_seeds)?;
 
         CreateMasterEditionV3Cpi::new(
             &ctx.accounts.token_metadata_program,
             CreateMasterEditionV3CpiAccounts {
                 edition: &ctx.accounts.master_edition_account,
                 mint: &ctx.accounts.position_mint.to_account_info(),
-                update_authority: &ctx.accounts.owner.to_account_info(),
-                mint_authority: &ctx.accounts.owner.to_account_info(),
+                update_authority: &pool_info,
+                mint_authority: &pool_info,
                 payer: &ctx.accounts.owner.to_account_info(),
                 metadata: &ctx.accounts.metadata_account,
                 token_program: &ctx.accounts.token_program,
                 system_program: &ctx.accounts.system_program,
                 rent: Some(&ctx.accounts.rent.to_account_info()),
             },
+            // A zero max supply makes the edition take over the mint, fixing the supply at one
             CreateMasterEditionV3InstructionArgs { max_supply: Some(0) },
         )
-        .invoke()?;
+        .invoke_signed(signer_seeds)?;
+        Ok(())
+    }
+
+    /// Validates the range and writes a fresh position for it.
+    pub fn init_position(
+        pool: &Account<Pool>,
+        position: &AccountLoader<Position>,
+        owner: Pubkey,
+        position_mint: Pubkey,
+        lower_bin_id: i32,
+        upper_bin_id: i32,
+    ) -> Result<()> {
+        // A single-bin position is allowed; limit orders are one
+        require!(lower_bin_id <= upper_bin_id, MyError::InvalidBinRange);
+        let bin_step = pool.bin_step as i32;
+        require!(
+            lower_bin_id % bin_step == 0 && upper_bin_id % bin_step == 0,
+            MyError::InvalidBinId
+        );
+        math::bin_count(lower_bin_id, upper_bin_id, pool.bin_step)?;
+
+        {
+            let mut position = position.load_init()?;
+            position.pool = pool.key();
+            position.owner = owner;
+            position.lower_bin_id = lower_bin_id;
+            position.upper_bin_id = upper_bin_id;
+            position.liquidity = 0;
+            position.position_mint = position_mint;
+            position.fees_owed_a = 0;
+            position.fees_owed_b = 0;
+        }
+
+        emit!(PositionOpened {
+            pool: pool.key(),
+            owner,
+            position: position.key(),
+            position_mint,
+            lower_bin_id,
+            upper_bin_id,
+        });
+        Ok(())
+    }
+}
+pub mod open_position_token_2022 {
+    use super::*;
+    pub fn handler(
+        ctx: Context<OpenPositionToken2022>,
+        lower_bin_id: i32,
+        upper_bin_id: i32,
+    ) -> Result<()> {
+        open_position::init_position(
+            &ctx.accounts.pool,
+            &ctx.accounts.position,
+            ctx.accounts.owner.key(),
+            ctx.accounts.position_mint.key(),
+            lower_bin_id,
+            upper_bin_id,
+        )?;
+
+        let pool = &ctx.accounts.pool;
+        let seeds = &[b"pool", pool.token_a_mint.as_ref(), pool.token_b_mint.as_ref(), &pool.bin_step.to_le_bytes(), &[pool.bump]];
+        let signer_seeds = &[&seeds[..]];
+        let uri = format!("{}{}", POSITION_NFT_URI_BASE, ctx.accounts.position.key());
+        let fields = [
+            ("pool", pool.key().to_string()),
+            ("lower_bin_id", lower_bin_id.to_string()),
+            ("upper_bin_id", upper_bin_id.to_string()),
+        ];
+        let metadata = TokenMetadata {
+            update_authority: OptionalNonZeroPubkey::try_from(Some(pool.key()))?,
+            mint: ctx.accounts.position_mint.key(),
+            name: POSITION_NFT_NAME.to_string(),
+            symbol: POSITION_NFT_SYMBOL.to_string(),
+            uri: uri.clone(),
+            additional_metadata: fields.iter().map(|(key, value)| (key.to_string(), value.clone())).collect(),
+        };
+
+        // The metadata extension grows the mint account, which must already hold the rent for it
+        let mint_info = ctx.accounts.position_mint.to_account_info();
+        let rent = Rent::get()?.minimum_balance(mint_info.data_len() + metadata.tlv_size_of()?);
+        let top_up = rent.saturating_sub(mint_info.lamports());
+        if top_up > 0 {
+            system_program::transfer(CpiContext::new(ctx.accounts.system_program.to_account_info(), Transfer { from: ctx.accounts.owner.to_account_info(), to: mint_info.clone() }), top_up)?;
+        }
+
+        token_interface::token_metadata_initialize(
+            CpiContext::new_with_signer(
+                ctx.accounts.token_program.to_account_info(),
+                TokenMetadataInitialize {
+                    program_id: ctx.accounts.token_program.to_account_info(),
+                    metadata: mint_info.clone(),
+                    update_authority: pool.to_account_info(),
+                    mint_authority: pool.to_account_info(),
+                    mint: mint_info.clone(),
+                },
+                signer_seeds,
+            ),
+            metadata.name,
+            metadata.symbol,
+            metadata.uri,
+        )?;
+        for (key, value) in fields {
+            token_interface::token_metadata_update_field(
+                CpiContext::new_with_signer(
+                    ctx.accounts.token_program.to_account_info(),
+                    TokenMetadataUpdateField {
+                        program_id: ctx.accounts.token_program.to_account_info(),
+                        metadata: mint_info.clone(),
+                        update_authority: pool.to_account_info(),
+                    },
+                    signer_seeds,
+                ),
+                Field::Key(key.to_string()),
+                value,
+            )?;
+        }
 
+        token_interface::mint_to(
+            CpiContext::new_with_signer(
+                ctx.accounts.token_program.to_account_info(),
+                MintTo {
+                    mint: mint_info.clone(),
+                    to: ctx.accounts.user_position_nft_account.to_account_info(),
+                    authority: pool.to_account_info(),
+                },
+                signer_seeds,
+            ),
+            1,
+        )?;
+        // Fix the supply at one
+        token_interface::set_authority(
+            CpiContext::new_with_signer(
+                ctx.accounts.token_program.to_account_info(),
+                SetAuthority {
+                    current_authority: pool.to_account_info(),
+                    account_or_mint: mint_info,
+                },
+                signer_seeds,
+            ),
+            AuthorityType::MintTokens,
+            None,
+        )?;
         Ok(())
     }
 }
 
+pub mod increase_position_length {
+    use super::*;
+    // The realloc constraint does the work: each call adds up to `Position::BINS_PER_ALLOCATION`
+    // zeroed bins until the account covers the position's range.
+    pub fn handler(_ctx: Context<IncreasePositionLength>) -> Result<()> {
+        Ok(())
+    }
+}
 // COMPLETELY REWRITTEN `add_liquidity` module to support chunking
 pub mod add_liquidity {
     use super::*;
+    #[allow(clippy::too_many_arguments)]
     pub fn handler<'info>(
         ctx: Context<'_, '_, 'info, 'info, AddLiquidity<'info>>,
         start_bin_id: i32,
+        end_bin_id: i32,
         liquidity_per_bin: u128,
+        max_amount_a: u64,
+        max_amount_b: u64,
+        expected_active_bin_id: i32,
+        max_active_bin_slippage: u32,
     ) -> Result<()> {
-        let pool = &mut ctx.accounts.pool;
-        let position = &mut ctx.accounts.position;
-
         require!(liquidity_per_bin > 0, MyError::ZeroLiquidity);
+        check_active_bin(&ctx.accounts.pool, expected_active_bin_id, max_active_bin_slippage)?;
+        let bin_count = check_range(&*ctx.accounts.position.load()?, start_bin_id, end_bin_id, ctx.accounts.pool.bin_step)?;
+        let bin_arrays = math::BinArrays::load(&ctx.accounts.pool, ctx.accounts.bitmap_extension.as_ref(), ctx.remaining_accounts)?;
+        deposit(
+            ctx.accounts,
+            &bin_arrays,
+            start_bin_id,
+            end_bin_id,
+            &vec![liquidity_per_bin; bin_count],
+            max_amount_a,
+            max_amount_b,
+        )
+    }
+
+    /// Fails if the active bin is more than `max_active_bin_slippage` bins away from the one
+    /// the depositor priced their transaction against.
+    pub fn check_active_bin(pool: &Pool, expected_active_bin_id: i32, max_active_bin_slippage: u32) -> Result<()> {
+        let slippage = pool.active_bin_id.abs_diff(expected_active_bin_id) / pool.bin_step as u32;
+        require!(slippage <= max_active_bin_slippage, MyError::ActiveBinSlippageExceeded);
+        Ok(())
+    }
+
+    /// Validates that `start_bin_id..=end_bin_id` is a chunk of the position and returns its bin count.
+    pub fn check_range(position: &Position, start_bin_id: i32, end_bin_id: i32, bin_step: u16) -> Result<usize> {
+        // Security check: ensure the chunk lies within the position's declared bounds
+        require!(
+            start_bin_id <= end_bin_id
+                && start_bin_id >= position.lower_bin_id
+                && end_bin_id <= position.upper_bin_id,
+            MyError::InvalidBinRange
+        );
+        require!(
+            (start_bin_id - position.lower_bin_id) % bin_step as i32 == 0,
+            MyError::InvalidBinId
+        );
+        math::bin_count(start_bin_id, end_bin_id, bin_step)
+    }
+
+    /// Mints `liquidities[i]` shares in the `i`-th bin of the chunk, pulling the tokens each
+    /// bin needs at its current composition.
+    pub fn deposit<'info>(
+        accounts: &mut AddLiquidity<'info>,
+        bin_arrays: &math::BinArrays<'info>,
+        start_bin_id: i32,
+        end_bin_id: i32,
+        liquidities: &[u128],
+        max_amount_a: u64,
+        max_amount_b: u64,
+    ) -> Result<()> {
+        let pool = &mut accounts.pool;
+        let (mut position, mut position_bins) = math::load_position_mut(&accounts.position, pool.bin_step)?;
 
+        require!(position.limit_order == LIMIT_ORDER_NONE, MyError::LimitOrderPosition);
+        let bin_count = check_range(&position, start_bin_id, end_bin_id, pool.bin_step)?;
+        require!(liquidities.len() == bin_count, MyError::InvalidBinCount);
+        require!(liquidities.iter().any(|liquidity| *liquidity > 0), MyError::ZeroLiquidity);
         let bin_step = pool.bin_step as i32;
-        let mut current_bin_id = start_bin_id;
+        math::update_rewards(pool, bin_arrays, Clock::get()?.unix_timestamp)?;
 
+        // 1. First Pass: Calculate total required token amounts for this chunk
+        let mut amounts_a = Vec::with_capacity(bin_count);
+        let mut amounts_b = Vec::with_capacity(bin_count);
         let mut total_required_a: u128 = 0;
         let mut total_required_b: u128 = 0;
-
-        // 1. First Pass: Calculate total required token amounts for this chunk
-        for _ in ctx.remaining_accounts.iter() {
-            // Security check: ensure the bin we are processing is within the position's declared bounds
-            require!(
-                current_bin_id >= position.lower_bin_id && current_bin_id <= position.upper_bin_id,
-                MyError::InvalidBinRange
-            );
-
-            // Use our new math helper for each bin in the chunk
+        for (offset, liquidity) in liquidities.iter().enumerate() {
+            let bin_id = start_bin_id + offset as i32 * bin_step;
             let (required_a, required_b) = math::calculate_required_for_bin(
                 pool.active_bin_id,
-                current_bin_id,
+                bin_id,
                 pool.bin_step,
-                liquidity_per_bin,
+                &math::BinReserves::from(&bin_arrays.bin(bin_id)?),
+                *liquidity,
             )?;
-
             total_required_a = total_required_a.checked_add(required_a).ok_or(MyError::MathOverflow)?;
             total_required_b = total_required_b.checked_add(required_b).ok_or(MyError::MathOverflow)?;
-
-            current_bin_id = current_bin_id.checked_add(bin_step).ok_or(MyError::MathOverflow)?;
+            amounts_a.push(u64::try_from(required_a).map_err(|_| MyError::MathOverflow)?);
+            amounts_b.push(u64::try_from(required_b).map_err(|_| MyError::MathOverflow)?);
         }
+        require!(
+            total_required_a <= max_amount_a as u128 && total_required_b <= max_amount_b as u128,
+            MyError::DepositAmountExceeded
+        );
 
         // 2. Transfer the calculated total tokens for this chunk
         if total_required_a > 0 {
             token_interface::transfer_checked(
                 CpiContext::new(
-                    ctx.accounts.token_a_program.to_account_info(),
+                    accounts.token_a_program.to_account_info(),
                     TransferChecked {
-                        from: ctx.accounts.user_token_a_account.to_account_info(),
-                        to: ctx.accounts.token_a_vault.to_account_info(),
-                        authority: ctx.accounts.owner.to_account_info(),
-                        mint: ctx.accounts.token_a_mint.to_account_info(),
+                        from: accounts.user_token_a_account.to_account_info(),
+                        to: accounts.token_a_vault.to_account_info(),
+                        authority: accounts.owner.to_account_info(),
+                        mint: accounts.token_a_mint.to_account_info(),
                     },
                 ),
                 total_required_a as u64, // Cast to u64, requires client-side check
-                ctx.accounts.token_a_mint.decimals,
+                accounts.token_a_mint.decimals,
             )?;
             pool.reserves_a = pool.reserves_a.checked_add(total_required_a as u64).ok_or(MyError::MathOverflow)?;
         }
         if total_required_b > 0 {
             token_interface::transfer_checked(
                 CpiContext::new(
-                    ctx.accounts.token_b_program.to_account_info(),
+                    accounts.token_b_program.to_account_info(),
                     TransferChecked {
-                        from: ctx.accounts.user_token_b_account.to_account_info(),
-                        to: ctx.accounts.token_b_vault.to_account_info(),
-                        authority: ctx.accounts.owner.to_account_info(),
-                        mint: ctx.accounts.token_b_mint.to_account_info(),
+                        from: accounts.user_token_b_account.to_account_info(),
+                        to: accounts.token_b_vault.to_account_info(),
+                        authority: accounts.owner.to_account_info(),
+                        mint: accounts.token_b_mint.to_account_info(),
                     },
                 ),
                 total_required_b as u64, // Cast to u64, requires client-side check
-                ctx.accounts.token_b_mint.decimals,
+                accounts.token_b_mint.decimals,
             )?;
             pool.reserves_b = pool.reserves_b.checked_add(total_required_b as u64).ok_or(MyError::MathOverflow)?;
         }
 
-        // 3. Second Pass: Update the liquidity in each bin account
-        current_bin_id = start_bin_id; // Reset counter for the second pass
-        for bin_account_info in ctx.remaining_accounts.iter() {
-            let (expected_bin_pda, _) = Pubkey::find_program_address(
-                &[b"bin", pool.key().as_ref(), &current_bin_id.to_le_bytes()],
-                ctx.program_id,
-            );
-            require_keys_eq!(bin_account_info.key(), expected_bin_pda, MyError::InvalidBinAccount);
-
-            let bin_loader = AccountLoader::<'_, Bin>::try_from(bin_account_info)?;
-            let mut bin = bin_loader.load_mut()?;
-            bin.liquidity = bin.liquidity.checked_add(liquidity_per_bin).ok_or(MyError::MathOverflow)?;
+        // 3. Second Pass: Deposit the tokens into each bin
+        let mut total_liquidity_added_in_chunk: u128 = 0;
+        for (offset, liquidity) in liquidities.iter().enumerate() {
+            let bin_id = start_bin_id + offset as i32 * bin_step;
+            let mut bin = bin_arrays.bin(bin_id)?;
 
-            current_bin_id = current_bin_id.checked_add(bin_step).ok_or(MyError::MathOverflow)?;
+            // Settle what the existing share earned before it grows, then checkpoint the bin
+            let position_bin = &mut position_bins[((bin_id - position.lower_bin_id) / bin_step) as usize];
+            math::settle_bin_fees(&mut position, position_bin, &bin)?;
+            math::settle_bin_rewards(&mut position, position_bin, &bin)?;
+            position_bin.liquidity_share = position_bin.liquidity_share.checked_add(*liquidity).ok_or(MyError::MathOverflow)?;
+            math::deposit_to_bin(&mut bin, amounts_a[offset] as u128, amounts_b[offset] as u128, *liquidity)?;
+            bin_arrays.set_bin(bin_id, bin)?;
+            total_liquidity_added_in_chunk = total_liquidity_added_in_chunk.checked_add(*liquidity).ok_or(MyError::MathOverflow)?;
         }
-        
+
         // 4. Update the total liquidity in the position account
-        let total_liquidity_added_in_chunk = liquidity_per_bin
-            .checked_mul(ctx.remaining_accounts.len() as u128)
-            .ok_or(MyError::MathOverflow)?;
-        
         position.liquidity = position.liquidity.checked_add(total_liquidity_added_in_chunk).ok_or(MyError::MathOverflow)?;
+        bin_arrays.sync_bitmap(pool)?;
 
+        emit!(LiquidityAdded {
+            pool: pool.key(),
+            owner: accounts.owner.key(),
+            position: accounts.position.key(),
+            start_bin_id,
+            end_bin_id,
+            liquidities: liquidities.to_vec(),
+            amounts_a,
+            amounts_b,
+        });
         Ok(())
     }
 }
 
+pub mod add_liquidity_by_strategy {
+    use super::*;
+    #[allow(clippy::too_many_arguments)]
+    pub fn handler<'info>(
+        ctx: Context<'_, '_, 'info, 'info, AddLiquidity<'info>>,
+        start_bin_id: i32,
+        end_bin_id: i32,
+        amount_a: u64,
+        amount_b: u64,
+        strategy: StrategyType,
+        expected_active_bin_id: i32,
+        max_active_bin_slippage: u32,
+    ) -> Result<()> {
+        require!(amount_a > 0 || amount_b > 0, MyError::ZeroAmount);
+        add_liquidity::check_active_bin(&ctx.accounts.pool, expected_active_bin_id, max_active_bin_slippage)?;
+        add_liquidity::check_range(&*ctx.accounts.position.load()?, start_bin_id, end_bin_id, ctx.accounts.pool.bin_step)?;
+        let bin_arrays = math::BinArrays::load(&ctx.accounts.pool, ctx.accounts.bitmap_extension.as_ref(), ctx.remaining_accounts)?;
+        let liquidities = math::liquidity_by_strategy(
+            &ctx.accounts.pool,
+            &bin_arrays,
+            start_bin_id,
+            end_bin_id,
+            amount_a,
+            amount_b,
+            strategy,
+        )?;
+        add_liquidity::deposit(ctx.accounts, &bin_arrays, start_bin_id, end_bin_id, &liquidities, amount_a, amount_b)
+    }
+}
+
+pub mod add_liquidity_one_side {
+    use super::*;
+    pub fn handler<'info>(
+        ctx: Context<'_, '_, 'info, 'info, AddLiquidity<'info>>,
+        start_bin_id: i32,
+        end_bin_id: i32,
+        amount: u64,
+        is_token_a: bool,
+    ) -> Result<()> {
+        require!(amount > 0, MyError::ZeroAmount);
+        let pool = &ctx.accounts.pool;
+        // Token A is only ever quoted above the active bin and token B below it
+        require!(
+            if is_token_a { start_bin_id > pool.active_bin_id } else { end_bin_id < pool.active_bin_id },
+            MyError::InvalidOneSidedRange
+        );
+        add_liquidity::check_range(&*ctx.accounts.position.load()?, start_bin_id, end_bin_id, pool.bin_step)?;
+
+        let bin_arrays = math::BinArrays::load(pool, ctx.accounts.bitmap_extension.as_ref(), ctx.remaining_accounts)?;
+        // A bin still holding the other token would pull it in proportionally
+        for bin_id in (start_bin_id..=end_bin_id).step_by(pool.bin_step as usize) {
+            let bin = bin_arrays.bin(bin_id)?;
+            let other_amount = if is_token_a { bin.amount_b } else { bin.amount_a };
+            require!(other_amount == 0, MyError::InvalidOneSidedRange);
+        }
+
+        let (amount_a, amount_b) = if is_token_a { (amount, 0) } else { (0, amount) };
+        let liquidities = math::liquidity_by_strategy(
+            pool,
+            &bin_arrays,
+            start_bin_id,
+            end_bin_id,
+            amount_a,
+            amount_b,
+            StrategyType::Spot,
+        )?;
+        add_liquidity::deposit(ctx.accounts, &bin_arrays, start_bin_id, end_bin_id, &liquidities, amount_a, amount_b)
+    }
+}
+
+pub mod add_liquidity_by_weight {
+    use super::*;
+    pub fn handler<'info>(
+        ctx: Context<'_, '_, 'info, 'info, AddLiquidity<'info>>,
+        amount_a: u64,
+        amount_b: u64,
+        bin_weights: Vec<BinWeight>,
+        expected_active_bin_id: i32,
+        max_active_bin_slippage: u32,
+    ) -> Result<()> {
+        require!(amount_a > 0 || amount_b > 0, MyError::ZeroAmount);
+        add_liquidity::check_active_bin(&ctx.accounts.pool, expected_active_bin_id, max_active_bin_slippage)?;
+        require!(!bin_weights.is_empty(), MyError::InvalidWeightDistribution);
+        let pool = &ctx.accounts.pool;
+        let bin_step = pool.bin_step as i32;
+
+        let mut weight_sum: u128 = 0;
+        let mut bin_ids = Vec::with_capacity(bin_weights.len());
+        for bin_weight in bin_weights.iter() {
+            let bin_id = bin_weight
+                .delta_id
+                .checked_mul(bin_step)
+                .and_then(|offset| pool.active_bin_id.checked_add(offset))
+                .ok_or(MyError::MathOverflow)?;
+            require!(!bin_ids.contains(&bin_id), MyError::InvalidWeightDistribution);
+            bin_ids.push(bin_id);
+            weight_sum += bin_weight.weight_bps as u128;
+        }
+        require!(weight_sum == BASIS_POINT_MAX, MyError::InvalidWeightDistribution);
+
+        // Spread the weights over the contiguous chunk they span; bins left out get nothing
+        let start_bin_id = *bin_ids.iter().min().ok_or(MyError::InvalidWeightDistribution)?;
+        let end_bin_id = *bin_ids.iter().max().ok_or(MyError::InvalidWeightDistribution)?;
+        let bin_count = add_liquidity::check_range(&*ctx.accounts.position.load()?, start_bin_id, end_bin_id, pool.bin_step)?;
+        let mut weights = vec![0u128; bin_count];
+        for (bin_id, bin_weight) in bin_ids.iter().zip(bin_weights.iter()) {
+            weights[((bin_id - start_bin_id) / bin_step) as usize] = bin_weight.weight_bps as u128;
+        }
+
+        let bin_arrays = math::BinArrays::load(pool, ctx.accounts.bitmap_extension.as_ref(), ctx.remaining_accounts)?;
+        let liquidities = math::liquidity_by_weights(pool, &bin_arrays, start_bin_id, &weights, amount_a, amount_b)?;
+        add_liquidity::deposit(ctx.accounts, &bin_arrays, start_bin_id, end_bin_id, &liquidities, amount_a, amount_b)
+    }
+}
+
 
 // NEW `modify_liquidity` module that replaces `rebalance_liquidity`
 pub mod modify_liquidity {
@@ -271,46 +776,47 @@ pub mod modify_liquidity {
         min_surplus_a_out: u64,
         min_surplus_b_out: u64,
     ) -> Result<()> {
+        // One set of bin arrays covers both the old and the new range
+        let bin_arrays = math::BinArrays::load(&ctx.accounts.pool, ctx.accounts.bitmap_extension.as_ref(), ctx.remaining_accounts)?;
+        math::update_rewards(&mut ctx.accounts.pool, &bin_arrays, Clock::get()?.unix_timestamp)?;
         let pool = &ctx.accounts.pool;
         let bin_step = pool.bin_step as i32;
-        let old_position = &mut ctx.accounts.old_position;
-        let new_position = &mut ctx.accounts.new_position;
+        let (mut old_position, mut old_position_bins) = math::load_position_mut(&ctx.accounts.old_position, pool.bin_step)?;
+        let (mut new_position, mut new_position_bins) = math::load_position_mut(&ctx.accounts.new_position, pool.bin_step)?;
+        require!(
+            old_position.limit_order == LIMIT_ORDER_NONE && new_position.limit_order == LIMIT_ORDER_NONE,
+            MyError::LimitOrderPosition
+        );
 
         let liquidity_to_move = old_position.liquidity;
         require!(liquidity_to_move > 0, MyError::PositionNotEmpty);
 
-        let expected_old_bins_count = ((old_position.upper_bin_id - old_position.lower_bin_id) / bin_step + 1) as usize;
-        let expected_new_bins_count = ((new_position.upper_bin_id - new_position.lower_bin_id) / bin_step + 1) as usize;
-        require!(ctx.remaining_accounts.len() == expected_old_bins_count + expected_new_bins_count, MyError::InvalidBinCount);
+        let new_bins_count = math::position_bin_count(&new_position, pool.bin_step)?;
 
-        let (old_bins_info, new_bins_info) = ctx.remaining_accounts.split_at(expected_old_bins_count);
-
-        let (principal_a, principal_b) = math::calculate_claimable_amounts(pool, old_position, liquidity_to_move)?;
-        let mut total_fees_a: u128 = 0;
-        let mut total_fees_b: u128 = 0;
+        let (principal_a, principal_b) = math::calculate_claimable_amounts(pool, &bin_arrays, &old_position, &old_position_bins, liquidity_to_move)?;
         let mut current_bin_id = old_position.lower_bin_id;
 
-        let liquidity_per_old_bin = liquidity_to_move.checked_div(expected_old_bins_count as u128).ok_or(MyError::MathOverflow)?;
-
-        for bin_info in old_bins_info.iter() {
-            let (expected_bin_pda, _) = Pubkey::find_program_address(&[b"bin", pool.key().as_ref(), &current_bin_id.to_le_bytes()], ctx.program_id);
-            require_keys_eq!(bin_info.key(), expected_bin_pda, MyError::InvalidBinAccount);
-            let bin_loader = AccountLoader::<'_, Bin>::try_from(bin_info)?;
-            let mut bin = bin_loader.load_mut()?;
-            let (fees_a, fees_b) = math::calculate_accrued_fees(old_position, &bin);
-            total_fees_a = total_fees_a.checked_add(fees_a as u128).ok_or(MyError::MathOverflow)?;
-            total_fees_b = total_fees_b.checked_add(fees_b as u128).ok_or(MyError::MathOverflow)?;
-            bin.liquidity = bin.liquidity.checked_sub(liquidity_per_old_bin).ok_or(MyError::MathOverflow)?;
+        for position_bin in old_position_bins.iter_mut() {
+            let mut bin = bin_arrays.bin(current_bin_id)?;
+            math::settle_bin_fees(&mut old_position, position_bin, &bin)?;
+            math::settle_bin_rewards(&mut old_position, position_bin, &bin)?;
+            math::withdraw_from_bin(&mut bin, position_bin.liquidity_share)?;
+            bin_arrays.set_bin(current_bin_id, bin)?;
+            position_bin.liquidity_share = 0;
             current_bin_id = current_bin_id.checked_add(bin_step).ok_or(MyError::MathOverflow)?;
         }
 
-        let total_claimable_a = principal_a.checked_add(total_fees_a).ok_or(MyError::MathOverflow)?;
-        let total_claimable_b = principal_b.checked_add(total_fees_b).ok_or(MyError::MathOverflow)?;
+        let total_claimable_a = principal_a.checked_add(old_position.fees_owed_a as u128).ok_or(MyError::MathOverflow)?;
+        let total_claimable_b = principal_b.checked_add(old_position.fees_owed_b as u128).ok_or(MyError::MathOverflow)?;
         old_position.liquidity = 0;
+        old_position.fees_owed_a = 0;
+        old_position.fees_owed_b = 0;
 
-        let (required_a, required_b) = math::calculate_required_token_amounts(pool, new_position.lower_bin_id, new_position.upper_bin_id, liquidity_to_move as u64)?;
-        let surplus_a = total_claimable_a.checked_sub(required_a as u128).ok_or(MyError::InsufficientLiquidity)?;
-        let surplus_b = total_claimable_b.checked_sub(required_b as u128).ok_or(MyError::InsufficientLiquidity)?;
+        // The new bins are priced after the old ones have been emptied, since the ranges may overlap
+        let liquidity_per_new_bin = liquidity_to_move.checked_div(new_bins_count as u128).ok_or(MyError::MathOverflow)?;
+        let (required_a, required_b) = math::calculate_required_token_amounts(pool, &bin_arrays, new_position.lower_bin_id, new_position.upper_bin_id, liquidity_per_new_bin)?;
+        let surplus_a = total_claimable_a.checked_sub(required_a).ok_or(MyError::InsufficientLiquidity)?;
+        let surplus_b = total_claimable_b.checked_sub(required_b).ok_or(MyError::InsufficientLiquidity)?;
         require!(surplus_a >= min_surplus_a_out as u128 && surplus_b >= min_surplus_b_out as u128, MyError::SlippageExceeded);
         
         let seeds = &[b"pool", pool.token_a_mint.as_ref(), pool.token_b_mint.as_ref(), &pool.bin_step.to_le_bytes(), &[ctx.accounts.pool.bump]];
@@ -349,34 +855,35 @@ pub mod modify_liquidity {
             )?;
         }
 
-        let liquidity_per_new_bin = liquidity_to_move.checked_div(expected_new_bins_count as u128).ok_or(MyError::MathOverflow)?;
-        let mut snapshot_a: u128 = 0;
-        let mut snapshot_b: u128 = 0;
         current_bin_id = new_position.lower_bin_id;
-        for bin_info in new_bins_info.iter() {
-            let (expected_bin_pda, _) = Pubkey::find_program_address(&[b"bin", pool.key().as_ref(), &current_bin_id.to_le_bytes()], ctx.program_id);
-            require_keys_eq!(bin_info.key(), expected_bin_pda, MyError::InvalidBinAccount);
-            let bin_loader = AccountLoader::<'_, Bin>::try_from(bin_info)?;
-            let mut bin = bin_loader.load_mut()?;
-            bin.liquidity = bin.liquidity.checked_add(liquidity_per_new_bin).ok_or(MyError::MathOverflow)?;
-            snapshot_a = snapshot_a.max(bin.fee_growth_per_unit_a);
-            snapshot_b = snapshot_b.max(bin.fee_growth_per_unit_b);
+        for position_bin in new_position_bins.iter_mut() {
+            let mut bin = bin_arrays.bin(current_bin_id)?;
+            let (required_a, required_b) = math::calculate_required_for_bin(pool.active_bin_id, current_bin_id, pool.bin_step, &math::BinReserves::from(&bin), liquidity_per_new_bin)?;
+            math::settle_bin_fees(&mut new_position, position_bin, &bin)?;
+            math::settle_bin_rewards(&mut new_position, position_bin, &bin)?;
+            position_bin.liquidity_share = position_bin.liquidity_share.checked_add(liquidity_per_new_bin).ok_or(MyError::MathOverflow)?;
+            math::deposit_to_bin(&mut bin, required_a, required_b, liquidity_per_new_bin)?;
+            bin_arrays.set_bin(current_bin_id, bin)?;
             current_bin_id = current_bin_id.checked_add(bin_step).ok_or(MyError::MathOverflow)?;
         }
-        
-        new_position.liquidity = new_position.liquidity.checked_add(liquidity_to_move).ok_or(MyError::MathOverflow)?;
-        new_position.fee_growth_snapshot_a = snapshot_a;
-        new_position.fee_growth_snapshot_b = snapshot_b;
-        
+
+        let liquidity_added = liquidity_per_new_bin.checked_mul(new_bins_count as u128).ok_or(MyError::MathOverflow)?;
+        new_position.liquidity = new_position.liquidity.checked_add(liquidity_added).ok_or(MyError::MathOverflow)?;
+
         emit!(LiquidityRebalanced {
             pool: pool.key(),
             owner: ctx.accounts.owner.key(),
-            old_position: old_position.key(),
-            new_position: new_position.key(),
+            old_position: ctx.accounts.old_position.key(),
+            new_position: ctx.accounts.new_position.key(),
             liquidity_moved: liquidity_to_move,
             new_lower_bin_id: new_position.lower_bin_id,
             new_upper_bin_id: new_position.upper_bin_id,
         });
+        // The surplus left the vaults, so it leaves the reserves as well
+        let pool = &mut ctx.accounts.pool;
+        pool.reserves_a = pool.reserves_a.checked_sub(surplus_a as u64).ok_or(MyError::MathOverflow)?;
+        pool.reserves_b = pool.reserves_b.checked_sub(surplus_b as u64).ok_or(MyError::MathOverflow)?;
+        bin_arrays.sync_bitmap(pool)?;
         Ok(())
     }
 }
@@ -388,36 +895,108 @@ pub mod swap {
         ctx: Context<'_, '_, 'info, 'info, Swap<'info>>,
         amount_in: u64,
         min_amount_out: u64,
+        limit_bin_id: Option<i32>,
     ) -> Result<()> {
         require!(amount_in > 0, MyError::ZeroAmount);
         let pool = &mut ctx.accounts.pool;
         let is_a_to_b = ctx.accounts.user_source_token_account.mint == pool.token_a_mint;
-        let (amount_out, final_active_bin_id) = if is_a_to_b {
-            math::swap_a_to_b(pool, amount_in, &ctx.remaining_accounts, ctx.program_id)?
-        } else {
-            math::swap_b_to_a(pool, amount_in, &ctx.remaining_accounts, ctx.program_id)?
-        };
-        require!(amount_out >= min_amount_out, MyError::SlippageExceeded);
-        pool.active_bin_id = final_active_bin_id;
+        let bin_arrays = math::BinArrays::load(pool, ctx.accounts.bitmap_extension.as_ref(), ctx.remaining_accounts)?;
+        // Rewards up to now belong to the bin that was active before the swap
+        math::update_rewards(pool, &bin_arrays, Clock::get()?.unix_timestamp)?;
+        let result = math::swap_exact_in(pool, amount_in, is_a_to_b, limit_bin_id, &bin_arrays, true)?;
+        require!(result.amount_out >= min_amount_out, MyError::SlippageExceeded);
+        bin_arrays.sync_bitmap(pool)?;
+        // Only the consumed input is pulled from the trader, so a limit stop refunds the rest implicitly
+        settle(ctx.accounts, is_a_to_b, &result)
+    }
+
+    /// Moves the tokens for an executed swap and updates the pool's price and reserve accounting.
+    pub(crate) fn settle(accounts: &mut Swap, is_a_to_b: bool, result: &math::SwapResult) -> Result<()> {
+        let math::SwapResult { amount_in, amount_out, fee, protocol_fee, final_bin_id, volatility, .. } = *result;
+        let pool = &mut accounts.pool;
+        let timestamp = Clock::get()?.unix_timestamp;
+        let start_bin_id = pool.active_bin_id;
+        // Close out the period the pre-swap price was active for
+        math::record_observation(&mut accounts.oracle, pool.active_bin_id, timestamp)?;
+        pool.active_bin_id = final_bin_id;
+        pool.volatility_accumulator = volatility.volatility_accumulator;
+        pool.volatility_reference = volatility.volatility_reference;
+        pool.index_reference = volatility.index_reference;
+        pool.last_update_timestamp = volatility.last_update_timestamp;
         let seeds = &[b"pool", pool.token_a_mint.as_ref(), pool.token_b_mint.as_ref(), &pool.bin_step.to_le_bytes(), &[pool.bump]];
         let signer_seeds = &[&seeds[..]];
-        let (source_token_program, destination_token_program) = if is_a_to_b { (ctx.accounts.token_a_program.to_account_info(), ctx.accounts.token_b_program.to_account_info()) } else { (ctx.accounts.token_b_program.to_account_info(), ctx.accounts.token_a_program.to_account_info()) };
-        let source_mint_info = if is_a_to_b { ctx.accounts.token_a_mint.to_account_info() } else { ctx.accounts.token_b_mint.to_account_info() };
-        let destination_mint_info = if is_a_to_b { ctx.accounts.token_b_mint.to_account_info() } else { ctx.accounts.token_a_mint.to_account_info() };
-        let source_decimals = if is_a_to_b { ctx.accounts.token_a_mint.decimals } else { ctx.accounts.token_b_mint.decimals };
-        let destination_decimals = if is_a_to_b { ctx.accounts.token_b_mint.decimals } else { ctx.accounts.token_a_mint.decimals };
-        token_interface::transfer_checked(CpiContext::new(source_token_program, TransferChecked { from: ctx.accounts.user_source_token_account.to_account_info(), to: ctx.accounts.source_vault.to_account_info(), authority: ctx.accounts.trader.to_account_info(), mint: source_mint_info }), amount_in, source_decimals)?;
-        token_interface::transfer_checked(CpiContext::new_with_signer(destination_token_program, TransferChecked { from: ctx.accounts.destination_vault.to_account_info(), to: ctx.accounts.user_destination_token_account.to_account_info(), authority: pool.to_account_info(), mint: destination_mint_info }, signer_seeds), amount_out, destination_decimals)?;
+        let (source_token_program, destination_token_program) = if is_a_to_b { (accounts.token_a_program.to_account_info(), accounts.token_b_program.to_account_info()) } else { (accounts.token_b_program.to_account_info(), accounts.token_a_program.to_account_info()) };
+        let source_mint_info = if is_a_to_b { accounts.token_a_mint.to_account_info() } else { accounts.token_b_mint.to_account_info() };
+        let destination_mint_info = if is_a_to_b { accounts.token_b_mint.to_account_info() } else { accounts.token_a_mint.to_account_info() };
+        let source_decimals = if is_a_to_b { accounts.token_a_mint.decimals } else { accounts.token_b_mint.decimals };
+        let destination_decimals = if is_a_to_b { accounts.token_b_mint.decimals } else { accounts.token_a_mint.decimals };
+        token_interface::transfer_checked(CpiContext::new(source_token_program, TransferChecked { from: accounts.user_source_token_account.to_account_info(), to: accounts.source_vault.to_account_info(), authority: accounts.trader.to_account_info(), mint: source_mint_info }), amount_in, source_decimals)?;
+        token_interface::transfer_checked(CpiContext::new_with_signer(destination_token_program, TransferChecked { from: accounts.destination_vault.to_account_info(), to: accounts.user_destination_token_account.to_account_info(), authority: pool.to_account_info(), mint: destination_mint_info }, signer_seeds), amount_out, destination_decimals)?;
+        // The protocol's cut stays in the vault but is tracked apart from LP reserves.
+        let amount_in_to_reserves = amount_in.checked_sub(protocol_fee).ok_or(MyError::MathOverflow)?;
         if is_a_to_b {
-            pool.reserves_a = pool.reserves_a.checked_add(amount_in).ok_or(MyError::MathOverflow)?;
+            pool.reserves_a = pool.reserves_a.checked_add(amount_in_to_reserves).ok_or(MyError::MathOverflow)?;
             pool.reserves_b = pool.reserves_b.checked_sub(amount_out).ok_or(MyError::MathOverflow)?;
+            pool.protocol_fees_a = pool.protocol_fees_a.checked_add(protocol_fee).ok_or(MyError::MathOverflow)?;
         } else {
-            pool.reserves_b = pool.reserves_b.checked_add(amount_in).ok_or(MyError::MathOverflow)?;
+            pool.reserves_b = pool.reserves_b.checked_add(amount_in_to_reserves).ok_or(MyError::MathOverflow)?;
             pool.reserves_a = pool.reserves_a.checked_sub(amount_out).ok_or(MyError::MathOverflow)?;
+            pool.protocol_fees_b = pool.protocol_fees_b.checked_add(protocol_fee).ok_or(MyError::MathOverflow)?;
         }
+        emit!(SwapEvent {
+            pool: pool.key(),
+            trader: accounts.trader.key(),
+            a_to_b: is_a_to_b,
+            amount_in,
+            amount_out,
+            fee,
+            protocol_fee,
+            start_bin_id,
+            end_bin_id: final_bin_id,
+            timestamp,
+        });
         Ok(())
     }
 }
+pub mod swap_exact_out {
+    use super::*;
+    pub fn handler<'info>(
+        ctx: Context<'_, '_, 'info, 'info, Swap<'info>>,
+        amount_out: u64,
+        max_amount_in: u64,
+    ) -> Result<()> {
+        require!(amount_out > 0, MyError::ZeroAmount);
+        let pool = &mut ctx.accounts.pool;
+        let is_a_to_b = ctx.accounts.user_source_token_account.mint == pool.token_a_mint;
+        let bin_arrays = math::BinArrays::load(pool, ctx.accounts.bitmap_extension.as_ref(), ctx.remaining_accounts)?;
+        math::update_rewards(pool, &bin_arrays, Clock::get()?.unix_timestamp)?;
+        let result = math::swap_exact_out(pool, amount_out, is_a_to_b, &bin_arrays)?;
+        require!(result.amount_in <= max_amount_in, MyError::SlippageExceeded);
+        bin_arrays.sync_bitmap(pool)?;
+        swap::settle(ctx.accounts, is_a_to_b, &result)
+    }
+}
+pub mod quote_swap {
+    use super::*;
+    pub fn handler<'info>(
+        ctx: Context<'_, '_, 'info, 'info, QuoteSwap<'info>>,
+        amount_in: u64,
+        a_to_b: bool,
+    ) -> Result<SwapQuote> {
+        require!(amount_in > 0, MyError::ZeroAmount);
+        let pool = &ctx.accounts.pool;
+        let bin_arrays = math::BinArrays::load(pool, ctx.accounts.bitmap_extension.as_ref(), ctx.remaining_accounts)?;
+        let result = math::swap_exact_in(pool, amount_in, a_to_b, None, &bin_arrays, false)?;
+        Ok(SwapQuote {
+            amount_in: result.amount_in,
+            amount_out: result.amount_out,
+            fee: result.fee,
+            protocol_fee: result.protocol_fee,
+            bins_crossed: result.bins_crossed,
+            final_active_bin_id: result.final_bin_id,
+        })
+    }
+}
 pub mod remove_liquidity {
     use super::*;
     pub fn handler<'info>(
@@ -426,32 +1005,29 @@ pub mod remove_liquidity {
         min_amount_a: u64,
         min_amount_b: u64,
     ) -> Result<()> {
-        let position = &mut ctx.accounts.position;
+        let (mut position, mut position_bins) = math::load_position_mut(&ctx.accounts.position, ctx.accounts.pool.bin_step)?;
         let pool = &mut ctx.accounts.pool;
         require!(liquidity_to_remove <= position.liquidity, MyError::InsufficientLiquidity);
-        let (principal_amount_a, principal_amount_b) = math::calculate_claimable_amounts(pool, position, liquidity_to_remove)?;
-        let mut total_fees_a: u64 = 0;
-        let mut total_fees_b: u64 = 0;
-        let mut final_fee_growth_a: u128 = position.fee_growth_snapshot_a;
-        let mut final_fee_growth_b: u128 = position.fee_growth_snapshot_b;
         let bin_step = pool.bin_step as i32;
-        let lower_bin_id = position.lower_bin_id;
-        let upper_bin_id = position.upper_bin_id;
-        let expected_bin_count = ((upper_bin_id - lower_bin_id) / bin_step + 1) as usize;
-        require!(ctx.remaining_accounts.len() == expected_bin_count, MyError::InvalidBinCount);
-        let mut current_bin_id = lower_bin_id;
-        for bin_info in ctx.remaining_accounts.iter() {
-            let (expected_pda, _) = Pubkey::find_program_address(&[b"bin", pool.key().as_ref(), &current_bin_id.to_le_bytes()], ctx.program_id);
-            require_keys_eq!(bin_info.key(), expected_pda, MyError::InvalidBinAccount);
-            let bin_loader = AccountLoader::<'_, Bin>::try_from(bin_info)?;
-            let bin = bin_loader.load()?;
-            let (fees_a, fees_b) = math::calculate_accrued_fees(position, &bin);
-            total_fees_a = total_fees_a.checked_add(fees_a).ok_or(MyError::MathOverflow)?;
-            total_fees_b = total_fees_b.checked_add(fees_b).ok_or(MyError::MathOverflow)?;
-            final_fee_growth_a = std::cmp::max(final_fee_growth_a, bin.fee_growth_per_unit_a);
-            final_fee_growth_b = std::cmp::max(final_fee_growth_b, bin.fee_growth_per_unit_b);
+        let bin_arrays = math::BinArrays::load(pool, ctx.accounts.bitmap_extension.as_ref(), ctx.remaining_accounts)?;
+        math::update_rewards(pool, &bin_arrays, Clock::get()?.unix_timestamp)?;
+        let (principal_amount_a, principal_amount_b) = math::calculate_claimable_amounts(pool, &bin_arrays, &position, &position_bins, liquidity_to_remove)?;
+        let total_liquidity = position.liquidity;
+        let mut liquidity_removed: u128 = 0;
+        let mut current_bin_id = position.lower_bin_id;
+        for position_bin in position_bins.iter_mut() {
+            let mut bin = bin_arrays.bin(current_bin_id)?;
+            math::settle_bin_fees(&mut position, position_bin, &bin)?;
+            math::settle_bin_rewards(&mut position, position_bin, &bin)?;
+            let liquidity_from_bin = math::share_to_remove(position_bin.liquidity_share, liquidity_to_remove, total_liquidity)?;
+            math::withdraw_from_bin(&mut bin, liquidity_from_bin)?;
+            bin_arrays.set_bin(current_bin_id, bin)?;
+            position_bin.liquidity_share = position_bin.liquidity_share.checked_sub(liquidity_from_bin).ok_or(MyError::MathOverflow)?;
+            liquidity_removed = liquidity_removed.checked_add(liquidity_from_bin).ok_or(MyError::MathOverflow)?;
             current_bin_id = current_bin_id.checked_add(bin_step).ok_or(MyError::MathOverflow)?;
         }
+        let total_fees_a = position.fees_owed_a;
+        let total_fees_b = position.fees_owed_b;
         let total_withdrawal_a = (principal_amount_a as u64).checked_add(total_fees_a).ok_or(MyError::MathOverflow)?;
         let total_withdrawal_b = (principal_amount_b as u64).checked_add(total_fees_b).ok_or(MyError::MathOverflow)?;
         require!(total_withdrawal_a >= min_amount_a, MyError::SlippageExceeded);
@@ -466,43 +1042,406 @@ pub mod remove_liquidity {
         if total_withdrawal_b > 0 {
             token_interface::transfer_checked(CpiContext::new_with_signer(ctx.accounts.token_b_program.to_account_info(), TransferChecked { from: ctx.accounts.token_b_vault.to_account_info(), to: ctx.accounts.user_token_b_account.to_account_info(), authority: pool.to_account_info(), mint: ctx.accounts.token_b_mint.to_account_info() }, signer_seeds), total_withdrawal_b, ctx.accounts.token_b_mint.decimals)?;
         }
-        position.liquidity = position.liquidity.checked_sub(liquidity_to_remove).ok_or(MyError::MathOverflow)?;
-        position.fee_growth_snapshot_a = final_fee_growth_a;
-        position.fee_growth_snapshot_b = final_fee_growth_b;
+        position.liquidity = position.liquidity.checked_sub(liquidity_removed).ok_or(MyError::MathOverflow)?;
+        position.fees_owed_a = 0;
+        position.fees_owed_b = 0;
+        // Withdrawing everything cancels (or completes) a limit order
+        if position.liquidity == 0 {
+            position.limit_order = LIMIT_ORDER_NONE;
+        }
+        bin_arrays.sync_bitmap(pool)?;
+        emit!(LiquidityRemoved {
+            pool: pool.key(),
+            owner: ctx.accounts.owner.key(),
+            position: ctx.accounts.position.key(),
+            liquidity_removed,
+            principal_a: principal_amount_a as u64,
+            principal_b: principal_amount_b as u64,
+            fees_a: total_fees_a,
+            fees_b: total_fees_b,
+        });
         Ok(())
     }
 }
-pub mod burn_empty_position {
+pub mod place_limit_order {
     use super::*;
-    pub fn handler(ctx: Context<BurnEmptyPosition>) -> Result<()> {
-        token_interface::burn(CpiContext::new(ctx.accounts.token_program.to_account_info(), Burn { mint: ctx.accounts.position_mint.to_account_info(), from: ctx.accounts.user_position_nft_account.to_account_info(), authority: ctx.accounts.owner.to_account_info() }), 1)?;
-        token_interface::close_account(CpiContext::new(ctx.accounts.token_program.to_account_info(), CloseAccount { account: ctx.accounts.user_position_nft_account.to_account_info(), destination: ctx.accounts.owner.to_account_info(), authority: ctx.accounts.owner.to_account_info() }))?;
+    pub fn handler<'info>(
+        ctx: Context<'_, '_, 'info, 'info, AddLiquidity<'info>>,
+        bin_id: i32,
+        amount: u64,
+        is_token_a: bool,
+    ) -> Result<()> {
+        let position_loader = ctx.accounts.position.clone();
+        {
+            let position = position_loader.load()?;
+            // Turning a position into an order locks it out of add and modify, so operators may not
+            require!(
+                holds_position_nft(&ctx.accounts.user_position_nft_account, &position, &ctx.accounts.owner.key()),
+                MyError::Unauthorized
+            );
+            require!(position.liquidity == 0, MyError::PositionNotEmpty);
+            require!(
+                position.lower_bin_id == bin_id && position.upper_bin_id == bin_id,
+                MyError::InvalidBinRange
+            );
+        }
+        let pool = ctx.accounts.pool.key();
+        let owner = ctx.accounts.owner.key();
+
+        add_liquidity_one_side::handler(ctx, bin_id, bin_id, amount, is_token_a)?;
+        position_loader.load_mut()?.limit_order = if is_token_a { LIMIT_ORDER_SELL_A } else { LIMIT_ORDER_SELL_B };
+
+        emit!(LimitOrderPlaced {
+            pool,
+            owner,
+            position: position_loader.key(),
+            bin_id,
+            amount,
+            is_token_a,
+        });
         Ok(())
     }
 }
+pub mod claim_limit_order {
+    use super::*;
+    /// Withdraws a filled order. Fills are not recorded: the order reads as filled while its
+    /// bin holds none of the token it sells, so if the price moves back through the bin and
+    /// converts it again, the order has to wait for the next fill (or be cancelled with
+    /// `remove_liquidity`).
+    pub fn handler<'info>(ctx: Context<'_, '_, 'info, 'info, RemoveLiquidity<'info>>) -> Result<()> {
+        let (bin_id, liquidity) = {
+            let position = ctx.accounts.position.load()?;
+            let bin_id = position.lower_bin_id;
+            let bin_arrays = math::BinArrays::load(&ctx.accounts.pool, ctx.accounts.bitmap_extension.as_ref(), ctx.remaining_accounts)?;
+            let bin = bin_arrays.bin(bin_id)?;
+            // A swap fully converts the bin before the price can move past it
+            let filled = match position.limit_order {
+                LIMIT_ORDER_SELL_A => bin.amount_a == 0,
+                LIMIT_ORDER_SELL_B => bin.amount_b == 0,
+                _ => return err!(MyError::NotLimitOrder),
+            };
+            require!(filled, MyError::LimitOrderNotFilled);
+            (bin_id, position.liquidity)
+        };
 
-//
+        emit!(LimitOrderClaimed {
+            pool: ctx.accounts.pool.key(),
+            owner: ctx.accounts.owner.key(),
+            position: ctx.accounts.position.key(),
+            bin_id,
+        });
+        remove_liquidity::handler(ctx, liquidity, 0, 0)
+    }
+}
+pub mod claim_fees {
+    use super::*;
+    pub fn handler<'info>(ctx: Context<'_, '_, 'info, 'info, ClaimFees<'info>>) -> Result<()> {
+        let (mut position, mut position_bins) = math::load_position_mut(&ctx.accounts.position, ctx.accounts.pool.bin_step)?;
+        let pool = &mut ctx.accounts.pool;
+        let bin_step = pool.bin_step as i32;
+        let bin_arrays = math::BinArrays::load(pool, None, ctx.remaining_accounts)?;
+        let mut current_bin_id = position.lower_bin_id;
+        for position_bin in position_bins.iter_mut() {
+            let bin = bin_arrays.bin(current_bin_id)?;
+            math::settle_bin_fees(&mut position, position_bin, &bin)?;
+            current_bin_id = current_bin_id.checked_add(bin_step).ok_or(MyError::MathOverflow)?;
+        }
+        let total_fees_a = position.fees_owed_a;
+        let total_fees_b = position.fees_owed_b;
+        // Fees are held in the vaults as part of the pool reserves until they are paid out.
+        pool.reserves_a = pool.reserves_a.checked_sub(total_fees_a).ok_or(MyError::MathOverflow)?;
+        pool.reserves_b = pool.reserves_b.checked_sub(total_fees_b).ok_or(MyError::MathOverflow)?;
+        let seeds = &[b"pool", pool.token_a_mint.as_ref(), pool.token_b_mint.as_ref(), &pool.bin_step.to_le_bytes(), &[pool.bump]];
+        let signer_seeds = &[&seeds[..]];
+        if total_fees_a > 0 {
+            token_interface::transfer_checked(CpiContext::new_with_signer(ctx.accounts.token_a_program.to_account_info(), TransferChecked { from: ctx.accounts.token_a_vault.to_account_info(), to: ctx.accounts.user_token_a_account.to_account_info(), authority: pool.to_account_info(), mint: ctx.accounts.token_a_mint.to_account_info() }, signer_seeds), total_fees_a, ctx.accounts.token_a_mint.decimals)?;
+        }
+        if total_fees_b > 0 {
+            token_interface::transfer_checked(CpiContext::new_with_signer(ctx.accounts.token_b_program.to_account_info(), TransferChecked { from: ctx.accounts.token_b_vault.to_account_info(), to: ctx.accounts.user_token_b_account.to_account_info(), authority: pool.to_account_info(), mint: ctx.accounts.token_b_mint.to_account_info() }, signer_seeds), total_fees_b, ctx.accounts.token_b_mint.decimals)?;
+        }
+        position.fees_owed_a = 0;
+        position.fees_owed_b = 0;
+        emit!(FeesClaimed {
+            pool: pool.key(),
+            owner: ctx.accounts.owner.key(),
+            position: ctx.accounts.position.key(),
+            fees_a: total_fees_a,
+            fees_b: total_fees_b,
+        });
+        Ok(())
+    }
+}
+pub mod initialize_reward {
+    use super::*;
+    pub fn handler(ctx: Context<InitializeReward>, reward_duration: u64, funder: Pubkey) -> Result<()> {
+        require!(reward_duration > 0, MyError::InvalidRewardDuration);
+        let pool = &mut ctx.accounts.pool;
+        let reward_index = pool.reward_infos.iter().position(|reward_info| !reward_info.initialized()).ok_or(MyError::RewardLimitReached)?;
+        pool.reward_infos[reward_index] = RewardInfo {
+            mint: ctx.accounts.reward_mint.key(),
+            vault: ctx.accounts.reward_vault.key(),
+            funder,
+            reward_duration,
+            ..Default::default()
+        };
+
+        emit!(RewardInitialized {
+            pool: pool.key(),
+            reward_index: reward_index as u8,
+            reward_mint: ctx.accounts.reward_mint.key(),
+            funder,
+            reward_duration,
+        });
+        Ok(())
+    }
+}
+pub mod fund_reward {
+    use super::*;
+    pub fn handler<'info>(ctx: Context<'_, '_, 'info, 'info, FundReward<'info>>, reward_index: u8, amount: u64) -> Result<()> {
+        require!(amount > 0, MyError::ZeroAmount);
+        let pool = &mut ctx.accounts.pool;
+        let reward_info = reward_info(pool, reward_index, &ctx.accounts.reward_mint, &ctx.accounts.reward_vault)?;
+        require_keys_eq!(reward_info.funder, ctx.accounts.funder.key(), MyError::Unauthorized);
+
+        // The current rate must be settled into the active bin before it changes
+        let bin_arrays = math::BinArrays::load(pool, None, ctx.remaining_accounts)?;
+        require!(bin_arrays.contains(pool.active_bin_id)?, MyError::MissingBinArray);
+        let now = Clock::get()?.unix_timestamp;
+        math::update_rewards(pool, &bin_arrays, now)?;
+        let reward_info = &mut pool.reward_infos[reward_index as usize];
+        math::fund_reward(reward_info, amount, now)?;
+        let reward_duration_end = reward_info.reward_duration_end;
+
+        token_interface::transfer_checked(CpiContext::new(ctx.accounts.token_program.to_account_info(), TransferChecked { from: ctx.accounts.funder_token_account.to_account_info(), to: ctx.accounts.reward_vault.to_account_info(), authority: ctx.accounts.funder.to_account_info(), mint: ctx.accounts.reward_mint.to_account_info() }), amount, ctx.accounts.reward_mint.decimals)?;
+
+        emit!(RewardFunded {
+            pool: pool.key(),
+            reward_index,
+            funder: ctx.accounts.funder.key(),
+            amount,
+            reward_duration_end,
+        });
+        Ok(())
+    }
+
+    /// The initialized reward at `reward_index`, checked against the mint and vault passed in.
+    pub fn reward_info(pool: &Pool, reward_index: u8, reward_mint: &InterfaceAccount<Mint>, reward_vault: &InterfaceAccount<TokenAccount>) -> Result<RewardInfo> {
+        let reward_info = *pool.reward_infos.get(reward_index as usize).ok_or(MyError::InvalidRewardIndex)?;
+        require!(reward_info.initialized(), MyError::InvalidRewardIndex);
+        require_keys_eq!(reward_info.mint, reward_mint.key(), MyError::InvalidMint);
+        require_keys_eq!(reward_info.vault, reward_vault.key(), MyError::InvalidVault);
+        Ok(reward_info)
+    }
+}
+pub mod claim_reward {
+    use super::*;
+    pub fn handler<'info>(ctx: Context<'_, '_, 'info, 'info, ClaimReward<'info>>, reward_index: u8) -> Result<()> {
+        let (mut position, mut position_bins) = math::load_position_mut(&ctx.accounts.position, ctx.accounts.pool.bin_step)?;
+        let pool = &mut ctx.accounts.pool;
+        fund_reward::reward_info(pool, reward_index, &ctx.accounts.reward_mint, &ctx.accounts.reward_vault)?;
+        let bin_step = pool.bin_step as i32;
+        let bin_arrays = math::BinArrays::load(pool, None, ctx.remaining_accounts)?;
+        math::update_rewards(pool, &bin_arrays, Clock::get()?.unix_timestamp)?;
+        let mut current_bin_id = position.lower_bin_id;
+        for position_bin in position_bins.iter_mut() {
+            let bin = bin_arrays.bin(current_bin_id)?;
+            math::settle_bin_rewards(&mut position, position_bin, &bin)?;
+            current_bin_id = current_bin_id.checked_add(bin_step).ok_or(MyError::MathOverflow)?;
+        }
+        let amount = position.rewards_owed[reward_index as usize];
+        position.rewards_owed[reward_index as usize] = 0;
+        let seeds = &[b"pool", pool.token_a_mint.as_ref(), pool.token_b_mint.as_ref(), &pool.bin_step.to_le_bytes(), &[pool.bump]];
+        let signer_seeds = &[&seeds[..]];
+        if amount > 0 {
+            token_interface::transfer_checked(CpiContext::new_with_signer(ctx.accounts.token_program.to_account_info(), TransferChecked { from: ctx.accounts.reward_vault.to_account_info(), to: ctx.accounts.user_reward_account.to_account_info(), authority: pool.to_account_info(), mint: ctx.accounts.reward_mint.to_account_info() }, signer_seeds), amount, ctx.accounts.reward_mint.decimals)?;
+        }
+        emit!(RewardClaimed {
+            pool: pool.key(),
+            owner: ctx.accounts.owner.key(),
+            position: ctx.accounts.position.key(),
+            reward_index,
+            amount,
+        });
+        Ok(())
+    }
+}
+pub mod approve_operator {
+    use super::*;
+    pub fn handler(ctx: Context<ApproveOperator>, operator: Pubkey, permissions: u8) -> Result<()> {
+        require!(permissions & !ALL_PERMISSIONS == 0, MyError::InvalidOperatorPermissions);
+        let mut position = ctx.accounts.position.load_mut()?;
+        position.operator = operator;
+        position.operator_permissions = permissions;
+        position.operator_owner = ctx.accounts.owner.key();
+
+        emit!(OperatorApproved {
+            position: ctx.accounts.position.key(),
+            owner: ctx.accounts.owner.key(),
+            operator,
+            permissions,
+        });
+        Ok(())
+    }
+}
+pub mod burn_empty_position {
+    use super::*;
+    pub fn handler(ctx: Context<BurnEmptyPosition>) -> Result<()> {
+        let mint_info = ctx.accounts.position_mint.to_account_info();
+        if *mint_info.owner == Token2022::id() {
+            // The metadata lives on the mint itself. Closing the token account and then the mint,
+            // whose close authority is the pool, returns the rent of both to the holder
+            let pool = &ctx.accounts.pool;
+            let seeds = &[b"pool", pool.token_a_mint.as_ref(), pool.token_b_mint.as_ref(), &pool.bin_step.to_le_bytes(), &[pool.bump]];
+            let signer_seeds = &[&seeds[..]];
+            token_interface::burn(CpiContext::new(ctx.accounts.token_program.to_account_info(), Burn { mint: mint_info.clone(), from: ctx.accounts.user_position_nft_account.to_account_info(), authority: ctx.accounts.owner.to_account_info() }), 1)?;
+            token_interface::close_account(CpiContext::new(ctx.accounts.token_program.to_account_info(), CloseAccount { account: ctx.accounts.user_position_nft_account.to_account_info(), destination: ctx.accounts.owner.to_account_info(), authority: ctx.accounts.owner.to_account_info() }))?;
+            token_interface::close_account(CpiContext::new_with_signer(ctx.accounts.token_program.to_account_info(), CloseAccount { account: mint_info, destination: ctx.accounts.owner.to_account_info(), authority: pool.to_account_info() }, signer_seeds))?;
+        } else {
+            // Metaplex burns the token and closes the token account, metadata and master edition,
+            // returning their rent to the holder
+            let (Some(metadata_account), Some(master_edition_account), Some(token_metadata_program)) = (
+                ctx.accounts.metadata_account.as_ref(),
+                ctx.accounts.master_edition_account.as_ref(),
+                ctx.accounts.token_metadata_program.as_ref(),
+            ) else {
+                return err!(MyError::MissingMetadataAccounts);
+            };
+            BurnNftCpi::new(
+                token_metadata_program,
+                BurnNftCpiAccounts {
+                    metadata: metadata_account,
+                    owner: &ctx.accounts.owner.to_account_info(),
+                    mint: &mint_info,
+                    token_account: &ctx.accounts.user_position_nft_account.to_account_info(),
+                    master_edition_account,
+                    spl_token_program: &ctx.accounts.token_program.to_account_info(),
+                    collection_metadata: None,
+                },
+            )
+            .invoke()?;
+        }
+        emit!(PositionClosed {
+            pool: ctx.accounts.pool.key(),
+            owner: ctx.accounts.owner.key(),
+            position: ctx.accounts.position.key(),
+            position_mint: ctx.accounts.position_mint.key(),
+        });
+        Ok(())
+    }
+}
+
+//
 // All `#[derive(Accounts)]` structs follow.
 //
 
+/// Positions are controlled by whoever holds their NFT, so they move with it.
+fn holds_position_nft(nft_account: &TokenAccount, position: &Position, holder: &Pubkey) -> bool {
+    nft_account.mint == position.position_mint && nft_account.owner == *holder && nft_account.amount == 1
+}
+
+/// Like `holds_position_nft`, but also accepts the operator the current holder approved for
+/// `permission`. `nft_account` must still be the holder's account.
+fn can_operate_position(nft_account: &TokenAccount, position: &Position, signer: &Pubkey, permission: u8) -> bool {
+    let holder = nft_account.owner;
+    holds_position_nft(nft_account, position, &holder)
+        && (holder == *signer
+            || (position.operator == *signer
+                && position.operator_owner == holder
+                && position.operator_permissions & permission != 0))
+}
+
 #[derive(Accounts)]
 pub struct GetPrice<'info> {
     pub pool: Account<'info, Pool>,
 }
 
+#[derive(Accounts)]
+pub struct GetTwap<'info> {
+    pub pool: Account<'info, Pool>,
+    #[account(seeds = [b"oracle", pool.key().as_ref()], bump = oracle.bump)]
+    pub oracle: Account<'info, Oracle>,
+}
+
+#[derive(Accounts)]
+#[instruction(length_to_add: u16)]
+pub struct IncreaseOracleLength<'info> {
+    pub pool: Account<'info, Pool>,
+    #[account(mut, seeds = [b"oracle", pool.key().as_ref()], bump = oracle.bump, realloc = 8 + 1 + 32 + 2 + 2 + 4 + 16 * (oracle.observations.len() + length_to_add as usize), realloc::payer = payer, realloc::zero = false)]
+    pub oracle: Account<'info, Oracle>,
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct InitializeGlobalConfig<'info> {
+    #[account(mut)]
+    pub authority: Signer<'info>,
+    #[account(init, seeds = [b"global_config"], bump, payer = authority, space = 8 + 1 + 32 + 4 + MAX_FEE_TIERS * 4)]
+    pub global_config: Account<'info, GlobalConfig>,
+    #[account(constraint = program.programdata_address()? == Some(program_data.key()) @ MyError::NotAdmin)]
+    pub program: Program<'info, crate::program::DloomFlow>,
+    #[account(constraint = program_data.upgrade_authority_address == Some(authority.key()) @ MyError::NotAdmin)]
+    pub program_data: Account<'info, ProgramData>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateGlobalConfig<'info> {
+    pub admin: Signer<'info>,
+    #[account(mut, seeds = [b"global_config"], bump = global_config.bump, has_one = admin @ MyError::NotAdmin)]
+    pub global_config: Account<'info, GlobalConfig>,
+}
+
+#[derive(Accounts)]
+pub struct UpdatePoolConfig<'info> {
+    pub admin: Signer<'info>,
+    #[account(seeds = [b"global_config"], bump = global_config.bump, has_one = admin @ MyError::NotAdmin)]
+    pub global_config: Account<'info, GlobalConfig>,
+    #[account(mut)]
+    pub pool: Account<'info, Pool>,
+}
+
+#[derive(Accounts)]
+pub struct CollectProtocolFees<'info> {
+    pub admin: Signer<'info>,
+    #[account(seeds = [b"global_config"], bump = global_config.bump, has_one = admin @ MyError::NotAdmin)]
+    pub global_config: Box<Account<'info, GlobalConfig>>,
+    #[account(mut)]
+    pub pool: Box<Account<'info, Pool>>,
+    #[account(address = pool.token_a_mint @ MyError::InvalidMint)]
+    pub token_a_mint: Box<InterfaceAccount<'info, Mint>>,
+    #[account(address = pool.token_b_mint @ MyError::InvalidMint)]
+    pub token_b_mint: Box<InterfaceAccount<'info, Mint>>,
+    #[account(mut, address = pool.token_a_vault @ MyError::InvalidVault)]
+    pub token_a_vault: Box<InterfaceAccount<'info, TokenAccount>>,
+    #[account(mut, address = pool.token_b_vault @ MyError::InvalidVault)]
+    pub token_b_vault: Box<InterfaceAccount<'info, TokenAccount>>,
+    #[account(mut, token::mint = token_a_mint)]
+    pub treasury_token_a_account: Box<InterfaceAccount<'info, TokenAccount>>,
+    #[account(mut, token::mint = token_b_mint)]
+    pub treasury_token_b_account: Box<InterfaceAccount<'info, TokenAccount>>,
+    pub token_a_program: Interface<'info, TokenInterface>,
+    pub token_b_program: Interface<'info, TokenInterface>,
+}
+
 #[derive(Accounts)]
 #[instruction(bin_step: u16)]
 pub struct InitializePool<'info> {
+    #[account(seeds = [b"global_config"], bump = global_config.bump)]
+    pub global_config: Account<'info, GlobalConfig>,
     pub token_a_mint: InterfaceAccount<'info, Mint>,
     pub token_b_mint: InterfaceAccount<'info, Mint>,
     #[account(mut)]
     pub payer: Signer<'info>,
-    #[account(init, seeds = [b"pool", token_a_mint.key().as_ref(), token_b_mint.key().as_ref(), &bin_step.to_le_bytes()], bump, payer = payer, space = 8 + 153)]
+    #[account(init, seeds = [b"pool", token_a_mint.key().as_ref(), token_b_mint.key().as_ref(), &bin_step.to_le_bytes()], bump, payer = payer, space = 8 + 605)]
     pub pool: Account<'info, Pool>,
     #[account(init, seeds = [b"vault", pool.key().as_ref(), token_a_mint.key().as_ref()], bump, payer = payer, token::mint = token_a_mint, token::authority = pool, token::token_program = token_a_program)]
     pub token_a_vault: InterfaceAccount<'info, TokenAccount>,
     #[account(init, seeds = [b"vault", pool.key().as_ref(), token_b_mint.key().as_ref()], bump, payer = payer, token::mint = token_b_mint, token::authority = pool, token::token_program = token_b_program)]
     pub token_b_vault: InterfaceAccount<'info, TokenAccount>,
+    #[account(init, seeds = [b"oracle", pool.key().as_ref()], bump, payer = payer, space = 8 + 1 + 32 + 2 + 2 + 4 + 16)]
+    pub oracle: Box<Account<'info, Oracle>>,
     pub system_program: Program<'info, System>,
     pub rent: Sysvar<'info, Rent>,
     pub token_a_program: Interface<'info, TokenInterface>,
@@ -510,10 +1449,10 @@ pub struct InitializePool<'info> {
 }
 
 #[derive(Accounts)]
-#[instruction(bin_id: i32)]
-pub struct InitializeBin<'info> {
-    #[account(init, seeds = [b"bin", pool.key().as_ref(), &bin_id.to_le_bytes()], bump, payer = payer, space = 8 + 16 + 16 + 16)]
-    pub bin: AccountLoader<'info, Bin>,
+#[instruction(index: i32)]
+pub struct InitializeBinArray<'info> {
+    #[account(init, seeds = [b"bin_array", pool.key().as_ref(), &index.to_le_bytes()], bump, payer = payer, space = 8 + std::mem::size_of::<BinArray>())]
+    pub bin_array: AccountLoader<'info, BinArray>,
     pub pool: Account<'info, Pool>,
     #[account(mut)]
     pub payer: Signer<'info>,
@@ -521,14 +1460,25 @@ pub struct InitializeBin<'info> {
 }
 
 #[derive(Accounts)]
+pub struct InitializeBitmapExtension<'info> {
+    #[account(init, seeds = [b"bitmap_extension", pool.key().as_ref()], bump, payer = payer, space = 8 + std::mem::size_of::<BinArrayBitmapExtension>())]
+    pub bitmap_extension: AccountLoader<'info, BinArrayBitmapExtension>,
+    pub pool: Account<'info, Pool>,
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(lower_bin_id: i32, upper_bin_id: i32)]
 pub struct OpenPosition<'info> {
     #[account(constraint = pool.token_a_mint == token_a_mint.key() && pool.token_b_mint == token_b_mint.key() @ MyError::InvalidMint)]
     pub pool: Box<Account<'info, Pool>>,
-    #[account(init, seeds = [b"position", position_mint.key().as_ref()], bump, payer = owner, space = 8 + 32 + 32 + 4 + 4 + 16 + 32 + 16 + 16)]
-    pub position: Box<Account<'info, Position>>,
+    #[account(init, seeds = [b"position", position_mint.key().as_ref()], bump, payer = owner, space = Position::space_for_range(lower_bin_id, upper_bin_id, pool.bin_step))]
+    pub position: AccountLoader<'info, Position>,
     #[account(mut)]
     pub owner: Signer<'info>,
-    #[account(init, payer = owner, mint::decimals = 0, mint::authority = owner, mint::freeze_authority = owner)]
+    #[account(init, payer = owner, mint::decimals = 0, mint::authority = pool, mint::freeze_authority = pool)]
     pub position_mint: Box<InterfaceAccount<'info, Mint>>,
     #[account(init, payer = owner, associated_token::mint = position_mint, associated_token::authority = owner)]
     pub user_position_nft_account: Box<InterfaceAccount<'info, TokenAccount>>,
@@ -549,12 +1499,59 @@ pub struct OpenPosition<'info> {
     pub rent: Sysvar<'info, Rent>,
 }
 
+#[derive(Accounts)]
+#[instruction(lower_bin_id: i32, upper_bin_id: i32)]
+pub struct OpenPositionToken2022<'info> {
+    pub pool: Box<Account<'info, Pool>>,
+    #[account(init, seeds = [b"position", position_mint.key().as_ref()], bump, payer = owner, space = Position::space_for_range(lower_bin_id, upper_bin_id, pool.bin_step))]
+    pub position: AccountLoader<'info, Position>,
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    #[account(
+        init,
+        payer = owner,
+        mint::decimals = 0,
+        mint::authority = pool,
+        mint::token_program = token_program,
+        extensions::metadata_pointer::authority = pool,
+        extensions::metadata_pointer::metadata_address = position_mint,
+        extensions::close_authority::authority = pool,
+    )]
+    pub position_mint: Box<InterfaceAccount<'info, Mint>>,
+    #[account(init, payer = owner, associated_token::mint = position_mint, associated_token::authority = owner, associated_token::token_program = token_program)]
+    pub user_position_nft_account: Box<InterfaceAccount<'info, TokenAccount>>,
+    pub system_program: Program<'info, System>,
+    pub token_program: Program<'info, Token2022>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
+}
+
+#[derive(Accounts)]
+pub struct IncreasePositionLength<'info> {
+    pub pool: Box<Account<'info, Pool>>,
+    #[account(
+        mut,
+        constraint = position.load()?.pool == pool.key() @ MyError::InvalidPool,
+        realloc = position.load()?.grown_space(position.as_ref().data_len(), pool.bin_step),
+        realloc::payer = payer,
+        realloc::zero = true,
+    )]
+    pub position: AccountLoader<'info, Position>,
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
 #[derive(Accounts)]
 pub struct AddLiquidity<'info> {
     #[account(mut)]
     pub pool: Box<Account<'info, Pool>>,
-    #[account(mut, has_one = owner, constraint = position.pool == pool.key() @ MyError::InvalidPool)]
-    pub position: Box<Account<'info, Position>>,
+    #[account(mut, constraint = position.load()?.pool == pool.key() @ MyError::InvalidPool)]
+    pub position: AccountLoader<'info, Position>,
+    #[account(constraint = can_operate_position(&user_position_nft_account, &*position.load()?, &owner.key(), PERMISSION_ADD_LIQUIDITY) @ MyError::Unauthorized)]
+    pub user_position_nft_account: Box<InterfaceAccount<'info, TokenAccount>>,
+    #[account(mut, seeds = [b"bitmap_extension", pool.key().as_ref()], bump)]
+    pub bitmap_extension: Option<AccountLoader<'info, BinArrayBitmapExtension>>,
+    // The position's holder or an operator; deposits are paid from this signer's accounts.
     #[account(mut)]
     pub owner: Signer<'info>,
     pub token_a_mint: Box<InterfaceAccount<'info, Mint>>,
@@ -577,15 +1574,22 @@ pub struct ModifyLiquidity<'info> {
     pub owner: Signer<'info>,
     #[account(mut)]
     pub pool: Box<Account<'info, Pool>>,
-    #[account(mut, has_one = owner @ MyError::Unauthorized, constraint = old_position.pool == pool.key() @ MyError::InvalidPool)]
-    pub old_position: Box<Account<'info, Position>>,
-    #[account(mut, has_one = owner @ MyError::Unauthorized, constraint = new_position.pool == pool.key() @ MyError::InvalidPool)]
-    pub new_position: Box<Account<'info, Position>>,
+    #[account(mut, constraint = old_position.load()?.pool == pool.key() @ MyError::InvalidPool)]
+    pub old_position: AccountLoader<'info, Position>,
+    #[account(constraint = can_operate_position(&old_position_nft_account, &*old_position.load()?, &owner.key(), PERMISSION_MODIFY_LIQUIDITY) @ MyError::Unauthorized)]
+    pub old_position_nft_account: Box<InterfaceAccount<'info, TokenAccount>>,
+    #[account(mut, constraint = new_position.load()?.pool == pool.key() @ MyError::InvalidPool)]
+    pub new_position: AccountLoader<'info, Position>,
+    #[account(constraint = can_operate_position(&new_position_nft_account, &*new_position.load()?, &owner.key(), PERMISSION_MODIFY_LIQUIDITY) && new_position_nft_account.owner == old_position_nft_account.owner @ MyError::Unauthorized)]
+    pub new_position_nft_account: Box<InterfaceAccount<'info, TokenAccount>>,
+    #[account(mut, seeds = [b"bitmap_extension", pool.key().as_ref()], bump)]
+    pub bitmap_extension: Option<AccountLoader<'info, BinArrayBitmapExtension>>,
     pub token_a_mint: Box<InterfaceAccount<'info, Mint>>,
     pub token_b_mint: Box<InterfaceAccount<'info, Mint>>,
-    #[account(mut, token::mint = pool.token_a_mint)]
+    // Surplus always goes to the holder, even when an operator signs.
+    #[account(mut, token::mint = pool.token_a_mint, token::authority = old_position_nft_account.owner)]
     pub user_token_a_account: Box<InterfaceAccount<'info, TokenAccount>>,
-    #[account(mut, token::mint = pool.token_b_mint)]
+    #[account(mut, token::mint = pool.token_b_mint, token::authority = old_position_nft_account.owner)]
     pub user_token_b_account: Box<InterfaceAccount<'info, TokenAccount>>,
     #[account(mut, address = pool.token_a_vault)]
     pub token_a_vault: Box<InterfaceAccount<'info, TokenAccount>>,
@@ -601,8 +1605,12 @@ pub struct RemoveLiquidity<'info> {
     pub owner: Signer<'info>,
     #[account(mut)]
     pub pool: Box<Account<'info, Pool>>,
-    #[account(mut, has_one = owner @ MyError::Unauthorized)]
-    pub position: Box<Account<'info, Position>>,
+    #[account(mut, constraint = position.load()?.pool == pool.key() @ MyError::InvalidPool)]
+    pub position: AccountLoader<'info, Position>,
+    #[account(constraint = holds_position_nft(&user_position_nft_account, &*position.load()?, &owner.key()) @ MyError::Unauthorized)]
+    pub user_position_nft_account: Box<InterfaceAccount<'info, TokenAccount>>,
+    #[account(mut, seeds = [b"bitmap_extension", pool.key().as_ref()], bump)]
+    pub bitmap_extension: Option<AccountLoader<'info, BinArrayBitmapExtension>>,
     pub token_a_mint: Box<InterfaceAccount<'info, Mint>>,
     pub token_b_mint: Box<InterfaceAccount<'info, Mint>>,
     #[account(mut, token::mint = pool.token_a_mint)]
@@ -617,17 +1625,117 @@ pub struct RemoveLiquidity<'info> {
     pub token_b_program: Interface<'info, TokenInterface>,
 }
 
+#[derive(Accounts)]
+pub struct InitializeReward<'info> {
+    #[account(mut)]
+    pub admin: Signer<'info>,
+    #[account(seeds = [b"global_config"], bump = global_config.bump, has_one = admin @ MyError::NotAdmin)]
+    pub global_config: Box<Account<'info, GlobalConfig>>,
+    #[account(mut)]
+    pub pool: Box<Account<'info, Pool>>,
+    pub reward_mint: Box<InterfaceAccount<'info, Mint>>,
+    #[account(init, seeds = [b"reward_vault", pool.key().as_ref(), reward_mint.key().as_ref()], bump, payer = admin, token::mint = reward_mint, token::authority = pool, token::token_program = token_program)]
+    pub reward_vault: Box<InterfaceAccount<'info, TokenAccount>>,
+    pub token_program: Interface<'info, TokenInterface>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct FundReward<'info> {
+    pub funder: Signer<'info>,
+    #[account(mut)]
+    pub pool: Box<Account<'info, Pool>>,
+    pub reward_mint: Box<InterfaceAccount<'info, Mint>>,
+    #[account(mut)]
+    pub reward_vault: Box<InterfaceAccount<'info, TokenAccount>>,
+    #[account(mut, token::mint = reward_mint, token::authority = funder)]
+    pub funder_token_account: Box<InterfaceAccount<'info, TokenAccount>>,
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct ClaimReward<'info> {
+    pub owner: Signer<'info>,
+    #[account(mut)]
+    pub pool: Box<Account<'info, Pool>>,
+    #[account(mut, constraint = position.load()?.pool == pool.key() @ MyError::InvalidPool)]
+    pub position: AccountLoader<'info, Position>,
+    #[account(constraint = holds_position_nft(&user_position_nft_account, &*position.load()?, &owner.key()) @ MyError::Unauthorized)]
+    pub user_position_nft_account: Box<InterfaceAccount<'info, TokenAccount>>,
+    pub reward_mint: Box<InterfaceAccount<'info, Mint>>,
+    #[account(mut)]
+    pub reward_vault: Box<InterfaceAccount<'info, TokenAccount>>,
+    #[account(mut, token::mint = reward_mint)]
+    pub user_reward_account: Box<InterfaceAccount<'info, TokenAccount>>,
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct ClaimFees<'info> {
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    #[account(mut)]
+    pub pool: Box<Account<'info, Pool>>,
+    #[account(mut, constraint = position.load()?.pool == pool.key() @ MyError::InvalidPool)]
+    pub position: AccountLoader<'info, Position>,
+    #[account(constraint = can_operate_position(&user_position_nft_account, &*position.load()?, &owner.key(), PERMISSION_CLAIM_FEES) @ MyError::Unauthorized)]
+    pub user_position_nft_account: Box<InterfaceAccount<'info, TokenAccount>>,
+    #[account(address = pool.token_a_mint @ MyError::InvalidMint)]
+    pub token_a_mint: Box<InterfaceAccount<'info, Mint>>,
+    #[account(address = pool.token_b_mint @ MyError::InvalidMint)]
+    pub token_b_mint: Box<InterfaceAccount<'info, Mint>>,
+    // Fees always go to the holder, even when an operator signs.
+    #[account(mut, token::mint = pool.token_a_mint, token::authority = user_position_nft_account.owner)]
+    pub user_token_a_account: Box<InterfaceAccount<'info, TokenAccount>>,
+    #[account(mut, token::mint = pool.token_b_mint, token::authority = user_position_nft_account.owner)]
+    pub user_token_b_account: Box<InterfaceAccount<'info, TokenAccount>>,
+    #[account(mut, address = pool.token_a_vault)]
+    pub token_a_vault: Box<InterfaceAccount<'info, TokenAccount>>,
+    #[account(mut, address = pool.token_b_vault)]
+    pub token_b_vault: Box<InterfaceAccount<'info, TokenAccount>>,
+    pub token_a_program: Interface<'info, TokenInterface>,
+    pub token_b_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct ApproveOperator<'info> {
+    pub owner: Signer<'info>,
+    #[account(mut)]
+    pub position: AccountLoader<'info, Position>,
+    #[account(constraint = holds_position_nft(&user_position_nft_account, &*position.load()?, &owner.key()) @ MyError::Unauthorized)]
+    pub user_position_nft_account: Box<InterfaceAccount<'info, TokenAccount>>,
+}
+
 #[derive(Accounts)]
 pub struct BurnEmptyPosition<'info> {
     #[account(mut)]
     pub owner: Signer<'info>,
-    #[account(mut, has_one = owner @ MyError::Unauthorized, constraint = position.liquidity == 0 @ MyError::PositionNotEmpty, close = owner)]
-    pub position: Box<Account<'info, Position>>,
-    #[account(mut, address = position.position_mint)]
+    #[account(constraint = position.load()?.pool == pool.key() @ MyError::InvalidPool)]
+    pub pool: Box<Account<'info, Pool>>,
+    #[account(mut, constraint = position.load()?.liquidity == 0 && position.load()?.fees_owed_a == 0 && position.load()?.fees_owed_b == 0 && position.load()?.rewards_owed == [0; NUM_REWARDS] @ MyError::PositionNotEmpty, close = owner)]
+    pub position: AccountLoader<'info, Position>,
+    #[account(mut, address = position.load()?.position_mint)]
     pub position_mint: InterfaceAccount<'info, Mint>,
-    #[account(mut, token::mint = position_mint)]
+    #[account(mut, token::mint = position_mint, constraint = holds_position_nft(&user_position_nft_account, &*position.load()?, &owner.key()) @ MyError::Unauthorized)]
     pub user_position_nft_account: InterfaceAccount<'info, TokenAccount>,
     pub token_program: Interface<'info, TokenInterface>,
+    // Required for NFTs minted by `open_position`; Token-2022 position NFTs have no Metaplex accounts.
+    #[account(mut, address = Metadata::find_pda(&position_mint.key()).0)]
+    /// CHECK: We are passing this to the CPI
+    pub metadata_account: Option<UncheckedAccount<'info>>,
+    #[account(mut, address = MasterEdition::find_pda(&position_mint.key()).0)]
+    /// CHECK: We are passing this to the CPI
+    pub master_edition_account: Option<UncheckedAccount<'info>>,
+    #[account(address = TOKEN_METADATA_ID)]
+    /// CHECK: We are passing this to the CPI
+    pub token_metadata_program: Option<UncheckedAccount<'info>>,
+}
+
+#[derive(Accounts)]
+pub struct QuoteSwap<'info> {
+    pub pool: Account<'info, Pool>,
+    #[account(seeds = [b"bitmap_extension", pool.key().as_ref()], bump)]
+    pub bitmap_extension: Option<AccountLoader<'info, BinArrayBitmapExtension>>,
 }
 
 #[derive(Accounts)]
@@ -636,6 +1744,10 @@ pub struct Swap<'info> {
     pub trader: Signer<'info>,
     #[account(mut)]
     pub pool: Box<Account<'info, Pool>>,
+    #[account(mut, seeds = [b"bitmap_extension", pool.key().as_ref()], bump)]
+    pub bitmap_extension: Option<AccountLoader<'info, BinArrayBitmapExtension>>,
+    #[account(mut, seeds = [b"oracle", pool.key().as_ref()], bump = oracle.bump)]
+    pub oracle: Box<Account<'info, Oracle>>,
     pub token_a_mint: Box<InterfaceAccount<'info, Mint>>,
     pub token_b_mint: Box<InterfaceAccount<'info, Mint>>,
     #[account(mut)]
@@ -659,4 +1771,142 @@ pub struct LiquidityRebalanced {
     pub liquidity_moved: u128,
     pub new_lower_bin_id: i32,
     pub new_upper_bin_id: i32,
-}
\ No newline at end of file
+}
+
+#[event]
+pub struct SwapEvent {
+    pub pool: Pubkey,
+    pub trader: Pubkey,
+    pub a_to_b: bool,
+    // Includes the fee.
+    pub amount_in: u64,
+    pub amount_out: u64,
+    pub fee: u64,
+    // Part of `fee` kept for the protocol.
+    pub protocol_fee: u64,
+    pub start_bin_id: i32,
+    pub end_bin_id: i32,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct PoolCreated {
+    pub pool: Pubkey,
+    pub token_a_mint: Pubkey,
+    pub token_b_mint: Pubkey,
+    pub bin_step: u16,
+    pub fee_rate: u16,
+    pub active_bin_id: i32,
+}
+
+#[event]
+pub struct BinArrayInitialized {
+    pub pool: Pubkey,
+    pub bin_array: Pubkey,
+    pub index: i32,
+}
+
+#[event]
+pub struct PositionOpened {
+    pub pool: Pubkey,
+    pub owner: Pubkey,
+    pub position: Pubkey,
+    pub position_mint: Pubkey,
+    pub lower_bin_id: i32,
+    pub upper_bin_id: i32,
+}
+
+#[event]
+pub struct LiquidityAdded {
+    pub pool: Pubkey,
+    pub owner: Pubkey,
+    pub position: Pubkey,
+    pub start_bin_id: i32,
+    pub end_bin_id: i32,
+    // Shares minted and tokens deposited in each bin from `start_bin_id` to `end_bin_id`.
+    pub liquidities: Vec<u128>,
+    pub amounts_a: Vec<u64>,
+    pub amounts_b: Vec<u64>,
+}
+
+#[event]
+pub struct LiquidityRemoved {
+    pub pool: Pubkey,
+    pub owner: Pubkey,
+    pub position: Pubkey,
+    pub liquidity_removed: u128,
+    pub principal_a: u64,
+    pub principal_b: u64,
+    pub fees_a: u64,
+    pub fees_b: u64,
+}
+
+#[event]
+pub struct FeesClaimed {
+    pub pool: Pubkey,
+    pub owner: Pubkey,
+    pub position: Pubkey,
+    pub fees_a: u64,
+    pub fees_b: u64,
+}
+
+#[event]
+pub struct PositionClosed {
+    pub pool: Pubkey,
+    pub owner: Pubkey,
+    pub position: Pubkey,
+    pub position_mint: Pubkey,
+}
+
+#[event]
+pub struct LimitOrderPlaced {
+    pub pool: Pubkey,
+    pub owner: Pubkey,
+    pub position: Pubkey,
+    pub bin_id: i32,
+    pub amount: u64,
+    pub is_token_a: bool,
+}
+
+#[event]
+pub struct LimitOrderClaimed {
+    pub pool: Pubkey,
+    pub owner: Pubkey,
+    pub position: Pubkey,
+    pub bin_id: i32,
+}
+
+#[event]
+pub struct RewardInitialized {
+    pub pool: Pubkey,
+    pub reward_index: u8,
+    pub reward_mint: Pubkey,
+    pub funder: Pubkey,
+    pub reward_duration: u64,
+}
+
+#[event]
+pub struct RewardFunded {
+    pub pool: Pubkey,
+    pub reward_index: u8,
+    pub funder: Pubkey,
+    pub amount: u64,
+    pub reward_duration_end: i64,
+}
+
+#[event]
+pub struct RewardClaimed {
+    pub pool: Pubkey,
+    pub owner: Pubkey,
+    pub position: Pubkey,
+    pub reward_index: u8,
+    pub amount: u64,
+}
+
+#[event]
+pub struct OperatorApproved {
+    pub position: Pubkey,
+    pub owner: Pubkey,
+    pub operator: Pubkey,
+    pub permissions: u8,
+}
@@ -1,6 +1,7 @@
 // FILE: lib.rs
 
 use anchor_lang::prelude::*;
+pub mod bin_math;
 pub mod constants;
 pub mod errors;
 pub mod instructions;
@@ -8,6 +9,7 @@ pub mod math;
 pub mod state;
 
 use instructions::*;
+use state::{BinWeight, StrategyType, SwapQuote};
 
 declare_id!("6fG9BGsHZjsV9Rie5fm2r9J9cfsqBG8kgTAicbHQtCwH"); // Replace with your actual Program ID
 
@@ -24,12 +26,66 @@ pub mod dloom_flow {
         instructions::initialize_pool::handler(ctx, bin_step, fee_rate, initial_bin_id)
     }
 
+    pub fn initialize_global_config(ctx: Context<InitializeGlobalConfig>) -> Result<()> {
+        instructions::initialize_global_config::handler(ctx)
+    }
+
+    pub fn add_fee_tier(ctx: Context<UpdateGlobalConfig>, bin_step: u16, fee_rate: u16) -> Result<()> {
+        instructions::add_fee_tier::handler(ctx, bin_step, fee_rate)
+    }
+
+    pub fn remove_fee_tier(ctx: Context<UpdateGlobalConfig>, bin_step: u16, fee_rate: u16) -> Result<()> {
+        instructions::remove_fee_tier::handler(ctx, bin_step, fee_rate)
+    }
+
+    pub fn set_admin(ctx: Context<UpdateGlobalConfig>, new_admin: Pubkey) -> Result<()> {
+        instructions::set_admin::handler(ctx, new_admin)
+    }
+
+    pub fn set_protocol_fee(ctx: Context<UpdatePoolConfig>, protocol_fee_bps: u16) -> Result<()> {
+        instructions::set_protocol_fee::handler(ctx, protocol_fee_bps)
+    }
+
+    pub fn set_dynamic_fee_parameters(
+        ctx: Context<UpdatePoolConfig>,
+        filter_period: u16,
+        decay_period: u16,
+        reduction_factor: u16,
+        variable_fee_control: u32,
+        max_volatility_accumulator: u32,
+    ) -> Result<()> {
+        instructions::set_dynamic_fee_parameters::handler(
+            ctx,
+            filter_period,
+            decay_period,
+            reduction_factor,
+            variable_fee_control,
+            max_volatility_accumulator,
+        )
+    }
+
+    pub fn collect_protocol_fees(ctx: Context<CollectProtocolFees>) -> Result<()> {
+        instructions::collect_protocol_fees::handler(ctx)
+    }
+
     pub fn get_price(ctx: Context<GetPrice>, bin_id: i32) -> Result<u128> {
         instructions::get_price::handler(ctx, bin_id)
     }
 
-    pub fn initialize_bin(ctx: Context<InitializeBin>, bin_id: i32) -> Result<()> {
-        instructions::initialize_bin::handler(ctx, bin_id)
+    pub fn increase_oracle_length(ctx: Context<IncreaseOracleLength>, length_to_add: u16) -> Result<()> {
+        instructions::increase_oracle_length::handler(ctx, length_to_add)
+    }
+
+    pub fn get_twap(ctx: Context<GetTwap>, seconds_ago: u32) -> Result<i32> {
+        instructions::get_twap::handler(ctx, seconds_ago)
+    }
+
+    pub fn initialize_bin_array(ctx: Context<InitializeBinArray>, index: i32) -> Result<()> {
+        instructions::initialize_bin_array::handler(ctx, index)
+    }
+
+    pub fn initialize_bitmap_extension(ctx: Context<InitializeBitmapExtension>) -> Result<()> {
+        instructions::initialize_bitmap_extension::handler(ctx)
     }
 
     pub fn open_position(
@@ -40,21 +96,149 @@ pub mod dloom_flow {
         instructions::open_position::handler(ctx, lower_bin_id, upper_bin_id)
     }
 
+    pub fn open_position_token_2022(
+        ctx: Context<OpenPositionToken2022>,
+        lower_bin_id: i32,
+        upper_bin_id: i32,
+    ) -> Result<()> {
+        instructions::open_position_token_2022::handler(ctx, lower_bin_id, upper_bin_id)
+    }
+
+    pub fn increase_position_length(ctx: Context<IncreasePositionLength>) -> Result<()> {
+        instructions::increase_position_length::handler(ctx)
+    }
+
     // UPDATED `add_liquidity` function signature
+    #[allow(clippy::too_many_arguments)]
     pub fn add_liquidity<'info>(
         ctx: Context<'_, '_, 'info, 'info, AddLiquidity<'info>>,
         start_bin_id: i32,
+        end_bin_id: i32,
         liquidity_per_bin: u128,
+        max_amount_a: u64,
+        max_amount_b: u64,
+        expected_active_bin_id: i32,
+        max_active_bin_slippage: u32,
+    ) -> Result<()> {
+        instructions::add_liquidity::handler(
+            ctx,
+            start_bin_id,
+            end_bin_id,
+            liquidity_per_bin,
+            max_amount_a,
+            max_amount_b,
+            expected_active_bin_id,
+            max_active_bin_slippage,
+        )
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn add_liquidity_by_strategy<'info>(
+        ctx: Context<'_, '_, 'info, 'info, AddLiquidity<'info>>,
+        start_bin_id: i32,
+        end_bin_id: i32,
+        amount_a: u64,
+        amount_b: u64,
+        strategy: StrategyType,
+        expected_active_bin_id: i32,
+        max_active_bin_slippage: u32,
     ) -> Result<()> {
-        instructions::add_liquidity::handler(ctx, start_bin_id, liquidity_per_bin)
+        instructions::add_liquidity_by_strategy::handler(
+            ctx,
+            start_bin_id,
+            end_bin_id,
+            amount_a,
+            amount_b,
+            strategy,
+            expected_active_bin_id,
+            max_active_bin_slippage,
+        )
+    }
+
+    pub fn add_liquidity_by_weight<'info>(
+        ctx: Context<'_, '_, 'info, 'info, AddLiquidity<'info>>,
+        amount_a: u64,
+        amount_b: u64,
+        bin_weights: Vec<BinWeight>,
+        expected_active_bin_id: i32,
+        max_active_bin_slippage: u32,
+    ) -> Result<()> {
+        instructions::add_liquidity_by_weight::handler(
+            ctx,
+            amount_a,
+            amount_b,
+            bin_weights,
+            expected_active_bin_id,
+            max_active_bin_slippage,
+        )
+    }
+
+    pub fn add_liquidity_one_side<'info>(
+        ctx: Context<'_, '_, 'info, 'info, AddLiquidity<'info>>,
+        start_bin_id: i32,
+        end_bin_id: i32,
+        amount: u64,
+        is_token_a: bool,
+    ) -> Result<()> {
+        instructions::add_liquidity_one_side::handler(ctx, start_bin_id, end_bin_id, amount, is_token_a)
+    }
+
+    pub fn place_limit_order<'info>(
+        ctx: Context<'_, '_, 'info, 'info, AddLiquidity<'info>>,
+        bin_id: i32,
+        amount: u64,
+        is_token_a: bool,
+    ) -> Result<()> {
+        instructions::place_limit_order::handler(ctx, bin_id, amount, is_token_a)
+    }
+
+    pub fn claim_limit_order<'info>(ctx: Context<'_, '_, 'info, 'info, RemoveLiquidity<'info>>) -> Result<()> {
+        instructions::claim_limit_order::handler(ctx)
+    }
+
+    pub fn initialize_reward(ctx: Context<InitializeReward>, reward_duration: u64, funder: Pubkey) -> Result<()> {
+        instructions::initialize_reward::handler(ctx, reward_duration, funder)
+    }
+
+    pub fn fund_reward<'info>(
+        ctx: Context<'_, '_, 'info, 'info, FundReward<'info>>,
+        reward_index: u8,
+        amount: u64,
+    ) -> Result<()> {
+        instructions::fund_reward::handler(ctx, reward_index, amount)
+    }
+
+    pub fn claim_reward<'info>(ctx: Context<'_, '_, 'info, 'info, ClaimReward<'info>>, reward_index: u8) -> Result<()> {
+        instructions::claim_reward::handler(ctx, reward_index)
+    }
+
+    pub fn approve_operator(ctx: Context<ApproveOperator>, operator: Pubkey, permissions: u8) -> Result<()> {
+        instructions::approve_operator::handler(ctx, operator, permissions)
     }
 
     pub fn swap<'info>(
         ctx: Context<'_, '_, 'info, 'info, Swap<'info>>,
         amount_in: u64,
         min_amount_out: u64,
+        limit_bin_id: Option<i32>,
     ) -> Result<()> {
-        instructions::swap::handler(ctx, amount_in, min_amount_out)
+        instructions::swap::handler(ctx, amount_in, min_amount_out, limit_bin_id)
+    }
+
+    pub fn swap_exact_out<'info>(
+        ctx: Context<'_, '_, 'info, 'info, Swap<'info>>,
+        amount_out: u64,
+        max_amount_in: u64,
+    ) -> Result<()> {
+        instructions::swap_exact_out::handler(ctx, amount_out, max_amount_in)
+    }
+
+    pub fn quote_swap<'info>(
+        ctx: Context<'_, '_, 'info, 'info, QuoteSwap<'info>>,
+        amount_in: u64,
+        a_to_b: bool,
+    ) -> Result<SwapQuote> {
+        instructions::quote_swap::handler(ctx, amount_in, a_to_b)
     }
 
     pub fn remove_liquidity<'info>(
@@ -66,6 +250,10 @@ pub mod dloom_flow {
         instructions::remove_liquidity::handler(ctx, liquidity_to_remove, min_amount_a, min_amount_b)
     }
 
+    pub fn claim_fees<'info>(ctx: Context<'_, '_, 'info, 'info, ClaimFees<'info>>) -> Result<()> {
+        instructions::claim_fees::handler(ctx)
+    }
+
     pub fn burn_empty_position(ctx: Context<BurnEmptyPosition>) -> Result<()> {
         instructions::burn_empty_position::handler(ctx)
     }
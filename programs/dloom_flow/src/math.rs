@@ -1,434 +1,832 @@
 // FILE: math.rs
 
+use std::cell::RefMut;
+
 use anchor_lang::prelude::*;
 use crate::{
-    constants::{BASIS_POINT_MAX, PRECISION},
+    bin_math::{self, BinArrayBitmap, BinSwapStep, DynamicFeeParams, VolatilityState, BitmapSlot, MathError, SwapBins, SwapParams},
+    constants::{BINS_PER_ARRAY, BIN_ARRAY_BITMAP_WORDS, MAX_BINS_PER_POSITION, MAX_ORACLE_LENGTH, NUM_REWARDS, PRECISION},
     errors::MyError,
-    state::{Bin, Pool, Position},
+    state::{Bin, BinArray, BinArrayBitmapExtension, Observation, Oracle, Pool, Position, PositionBin, RewardInfo, StrategyType},
 };
 
-pub fn get_price_at_bin(bin_id: i32, bin_step: u16) -> Result<u128> {
-    if bin_step == 0 {
-        return err!(MyError::InvalidBinStep);
-    }
-    let basis_point_step = bin_step as u128;
-    let power = bin_id.unsigned_abs() as u128;
-    let base = BASIS_POINT_MAX
-        .checked_add(basis_point_step)
-        .ok_or(MyError::MathOverflow)?;
+pub use crate::bin_math::{calculate_required_for_bin, get_price_at_bin, BinReserves, SwapResult};
 
-    let price_ratio = power_fp(base, power)?;
+/// Tokens needed to add `liquidity_per_bin` shares to every bin from `lower_bin_id` to
+/// `upper_bin_id`, priced against each bin's current inventory.
+pub fn calculate_required_token_amounts(
+    pool: &Account<Pool>,
+    bin_arrays: &BinArrays,
+    lower_bin_id: i32,
+    upper_bin_id: i32,
+    liquidity_per_bin: u128,
+) -> Result<(u128, u128)> {
+    let mut amount_a: u128 = 0;
+    let mut amount_b: u128 = 0;
 
-    if bin_id >= 0 {
-        Ok(price_ratio)
-    } else {
-        PRECISION
-            .checked_mul(PRECISION)
-            .ok_or(MyError::MathOverflow)?
-            .checked_div(price_ratio)
-            .ok_or(MyError::MathOverflow.into())
+    for bin_id in (lower_bin_id..=upper_bin_id).step_by(pool.bin_step as usize) {
+        let bin = bin_arrays.bin(bin_id)?;
+        let (amount_a_in_bin, amount_b_in_bin) = calculate_required_for_bin(
+            pool.active_bin_id,
+            bin_id,
+            pool.bin_step,
+            &BinReserves::from(&bin),
+            liquidity_per_bin,
+        )?;
+        amount_a = amount_a
+            .checked_add(amount_a_in_bin)
+            .ok_or(MyError::MathOverflow)?;
+        amount_b = amount_b
+            .checked_add(amount_b_in_bin)
+            .ok_or(MyError::MathOverflow)?;
     }
+    Ok((amount_a, amount_b))
 }
 
-fn power_fp(base: u128, exp: u128) -> Result<u128> {
-    let mut res = PRECISION;
-    let mut base_fp = base;
-    let mut exp_rem = exp;
+/// Liquidity to mint in each bin from `lower_bin_id` to `upper_bin_id` so that the deposit
+/// follows `strategy` around the active bin and costs at most `amount_a` and `amount_b`.
+pub fn liquidity_by_strategy(
+    pool: &Account<Pool>,
+    bin_arrays: &BinArrays,
+    lower_bin_id: i32,
+    upper_bin_id: i32,
+    amount_a: u64,
+    amount_b: u64,
+    strategy: StrategyType,
+) -> Result<Vec<u128>> {
+    let bin_step = pool.bin_step as i32;
+    let distance = |bin_id: i32| (bin_id.abs_diff(pool.active_bin_id) / bin_step as u32) as u128;
+    let max_distance = distance(lower_bin_id).max(distance(upper_bin_id));
+    let weights: Vec<u128> = (lower_bin_id..=upper_bin_id)
+        .step_by(pool.bin_step as usize)
+        .map(|bin_id| match strategy {
+            StrategyType::Spot => 1,
+            StrategyType::Curve => max_distance + 1 - distance(bin_id),
+            StrategyType::BidAsk => distance(bin_id) + 1,
+        })
+        .collect();
+    liquidity_by_weights(pool, bin_arrays, lower_bin_id, &weights, amount_a, amount_b)
+}
 
-    if exp == 0 {
-        return Ok(PRECISION);
+/// Liquidity to mint in each bin from `lower_bin_id` upwards, proportional to `weights[i]`
+/// for the `i`-th bin and costing at most `amount_a` and `amount_b`.
+pub fn liquidity_by_weights(
+    pool: &Account<Pool>,
+    bin_arrays: &BinArrays,
+    lower_bin_id: i32,
+    weights: &[u128],
+    amount_a: u64,
+    amount_b: u64,
+) -> Result<Vec<u128>> {
+    let mut reference_liquidity = Vec::with_capacity(weights.len());
+    let mut reference_amounts = Vec::with_capacity(weights.len());
+    for (offset, weight) in weights.iter().enumerate() {
+        let bin_id = lower_bin_id + offset as i32 * pool.bin_step as i32;
+        let liquidity = weight.checked_mul(PRECISION).ok_or(MyError::MathOverflow)?;
+        let bin = bin_arrays.bin(bin_id)?;
+        reference_amounts.push(calculate_required_for_bin(
+            pool.active_bin_id,
+            bin_id,
+            pool.bin_step,
+            &BinReserves::from(&bin),
+            liquidity,
+        )?);
+        reference_liquidity.push(liquidity);
     }
 
-    while exp_rem > 0 {
-        if exp_rem % 2 == 1 {
-            res = res
-                .checked_mul(base_fp)
-                .ok_or(MyError::MathOverflow)?
-                .checked_div(BASIS_POINT_MAX)
-                .ok_or(MyError::MathOverflow)?;
-        }
-        base_fp = base_fp
-            .checked_mul(base_fp)
-            .ok_or(MyError::MathOverflow)?
-            .checked_div(BASIS_POINT_MAX)
-            .ok_or(MyError::MathOverflow)?;
-        exp_rem /= 2;
-    }
-    Ok(res)
+    Ok(bin_math::scale_liquidity_to_amounts(
+        &reference_liquidity,
+        &reference_amounts,
+        amount_a,
+        amount_b,
+    )?)
 }
 
-pub fn calculate_required_for_bin(
-    active_bin_id: i32,
-    bin_id: i32,
-    bin_step: u16,
-    liquidity_amount: u128,
+/// Tokens paid out for `liquidity_to_remove`, taken pro rata from each of the position's
+/// bins at that bin's actual composition.
+pub fn calculate_claimable_amounts(
+    pool: &Account<Pool>,
+    bin_arrays: &BinArrays,
+    position: &Position,
+    position_bins: &[PositionBin],
+    liquidity_to_remove: u128,
 ) -> Result<(u128, u128)> {
-    let mut required_a: u128 = 0;
-    let mut required_b: u128 = 0;
-
-    if bin_id > active_bin_id {
-        // Bins above the active price are denominated in Token A
-        required_a = liquidity_amount;
-    } else if bin_id < active_bin_id {
-        // Bins below the active price are denominated in Token B
-        let price = get_price_at_bin(bin_id, bin_step)?;
-        required_b = liquidity_amount
-            .checked_mul(price)
-            .ok_or(MyError::MathOverflow)?
-            .checked_div(PRECISION)
+    let mut amount_a: u128 = 0;
+    let mut amount_b: u128 = 0;
+
+    let mut bin_id = position.lower_bin_id;
+    for position_bin in position_bins {
+        let liquidity_from_bin = share_to_remove(
+            position_bin.liquidity_share,
+            liquidity_to_remove,
+            position.liquidity,
+        )?;
+        let bin = bin_arrays.bin(bin_id)?;
+        let (amount_a_in_bin, amount_b_in_bin) =
+            bin_math::calculate_withdrawal_for_bin(&BinReserves::from(&bin), liquidity_from_bin)?;
+        amount_a = amount_a
+            .checked_add(amount_a_in_bin)
             .ok_or(MyError::MathOverflow)?;
-    } else {
-        // The active bin can contain both tokens
-        let price = get_price_at_bin(bin_id, bin_step)?;
-        required_a = liquidity_amount;
-        required_b = liquidity_amount
-            .checked_mul(price)
-            .ok_or(MyError::MathOverflow)?
-            .checked_div(PRECISION)
+        amount_b = amount_b
+            .checked_add(amount_b_in_bin)
+            .ok_or(MyError::MathOverflow)?;
+        bin_id = bin_id
+            .checked_add(pool.bin_step as i32)
             .ok_or(MyError::MathOverflow)?;
     }
 
-    Ok((required_a, required_b))
+    Ok((amount_a, amount_b))
 }
 
+/// Borrows a position's header together with one `PositionBin` per bin of its range, which
+/// follow the header in the account.
+pub fn load_position_mut<'a>(position: &'a AccountLoader<Position>, bin_step: u16) -> Result<(RefMut<'a, Position>, RefMut<'a, [PositionBin]>)> {
+    // Runs the loader's owner, discriminator and writability checks
+    let bin_count = position_bin_count(&*position.load_mut()?, bin_step)?;
+    let position_info: &AccountInfo = position.as_ref();
+    let data = position_info.try_borrow_mut_data()?;
+    require!(data.len() >= Position::space(bin_count), MyError::PositionNotAllocated);
+    let (header, bins) = RefMut::map_split(data, |data| data[8..Position::space(bin_count)].split_at_mut(std::mem::size_of::<Position>()));
+    Ok((RefMut::map(header, |header| bytemuck::from_bytes_mut(header)), RefMut::map(bins, |bins| bytemuck::cast_slice_mut(bins))))
+}
 
-pub fn calculate_required_token_amounts(
-    pool: &Account<Pool>,
-    lower_bin_id: i32,
-    upper_bin_id: i32,
-    amount_to_deposit: u64,
-) -> Result<(u64, u64)> {
-    let mut amount_a: u128 = 0;
-    let mut amount_b: u128 = 0;
+/// Number of bins spanned by a position, validated against `MAX_BINS_PER_POSITION`.
+pub fn position_bin_count(position: &Position, bin_step: u16) -> Result<usize> {
+    bin_count(position.lower_bin_id, position.upper_bin_id, bin_step)
+}
 
-    let num_bins = ((upper_bin_id - lower_bin_id) as u128 / pool.bin_step as u128)
-        .checked_add(1)
+pub fn bin_count(lower_bin_id: i32, upper_bin_id: i32, bin_step: u16) -> Result<usize> {
+    if bin_step == 0 {
+        return err!(MyError::InvalidBinStep);
+    }
+    let span = upper_bin_id
+        .checked_sub(lower_bin_id)
         .ok_or(MyError::MathOverflow)?;
-    if num_bins == 0 {
-        return Ok((0, 0));
+    let count = (span / bin_step as i32 + 1) as usize;
+    require!(count <= MAX_BINS_PER_POSITION, MyError::RangeTooWide);
+    Ok(count)
+}
+
+/// Portion of a bin's share removed when `liquidity_to_remove` is taken pro rata from the position.
+pub fn share_to_remove(share: u128, liquidity_to_remove: u128, total_liquidity: u128) -> Result<u128> {
+    if total_liquidity == 0 {
+        return Ok(0);
     }
+    share
+        .checked_mul(liquidity_to_remove)
+        .ok_or(MyError::MathOverflow)?
+        .checked_div(total_liquidity)
+        .ok_or(MyError::MathOverflow.into())
+}
+
+pub fn calculate_accrued_fees(position_bin: &PositionBin, bin: &Bin) -> (u64, u64) {
+    let share = position_bin.liquidity_share;
+    let fees_a = bin_math::accrued_fee(
+        bin.fee_growth_per_unit_a,
+        position_bin.fee_growth_checkpoint_a,
+        share,
+    );
+    let fees_b = bin_math::accrued_fee(
+        bin.fee_growth_per_unit_b,
+        position_bin.fee_growth_checkpoint_b,
+        share,
+    );
 
-    let liquidity_per_bin = (amount_to_deposit as u128)
-        .checked_div(num_bins)
+    (fees_a, fees_b)
+}
+
+/// Moves fees accrued in one bin into the position's owed balance and resets its checkpoint.
+/// Must be called before the position's share in that bin changes.
+pub fn settle_bin_fees(position: &mut Position, position_bin: &mut PositionBin, bin: &Bin) -> Result<()> {
+    let (fees_a, fees_b) = calculate_accrued_fees(position_bin, bin);
+    position.fees_owed_a = position
+        .fees_owed_a
+        .checked_add(fees_a)
+        .ok_or(MyError::MathOverflow)?;
+    position.fees_owed_b = position
+        .fees_owed_b
+        .checked_add(fees_b)
         .ok_or(MyError::MathOverflow)?;
+    position_bin.fee_growth_checkpoint_a = bin.fee_growth_per_unit_a;
+    position_bin.fee_growth_checkpoint_b = bin.fee_growth_per_unit_b;
+    Ok(())
+}
 
-    for bin_id in (lower_bin_id..=upper_bin_id).step_by(pool.bin_step as usize) {
-        if bin_id > pool.active_bin_id {
-            // Above active price: only token A is required
-            amount_a = amount_a
-                .checked_add(liquidity_per_bin)
-                .ok_or(MyError::MathOverflow)?;
-        } else if bin_id < pool.active_bin_id {
-            // Below active price: only token B is required
-            let price = get_price_at_bin(bin_id, pool.bin_step)?;
-            let amount_b_in_bin = liquidity_per_bin
-                .checked_mul(price)
-                .ok_or(MyError::MathOverflow)?
-                .checked_div(PRECISION)
-                .ok_or(MyError::MathOverflow)?;
-            amount_b = amount_b
-                .checked_add(amount_b_in_bin)
-                .ok_or(MyError::MathOverflow)?;
-        } else {
-            // At active price: both tokens are required
-            let price = get_price_at_bin(bin_id, pool.bin_step)?;
-            let amount_b_in_bin = liquidity_per_bin
-                .checked_mul(price)
-                .ok_or(MyError::MathOverflow)?
-                .checked_div(PRECISION)
-                .ok_or(MyError::MathOverflow)?;
-            amount_a = amount_a
-                .checked_add(liquidity_per_bin)
-                .ok_or(MyError::MathOverflow)?;
-            amount_b = amount_b
-                .checked_add(amount_b_in_bin)
-                .ok_or(MyError::MathOverflow)?;
+/// Moves rewards accrued in one bin into the position's owed balances and resets its
+/// checkpoints. Like `settle_bin_fees`, must run before the position's share changes.
+pub fn settle_bin_rewards(position: &mut Position, position_bin: &mut PositionBin, bin: &Bin) -> Result<()> {
+    let share = position_bin.liquidity_share;
+    for reward_index in 0..NUM_REWARDS {
+        let reward = bin_math::accrued_fee(
+            bin.reward_growth_per_unit[reward_index],
+            position_bin.reward_growth_checkpoints[reward_index],
+            share,
+        );
+        position.rewards_owed[reward_index] = position.rewards_owed[reward_index]
+            .checked_add(reward)
+            .ok_or(MyError::MathOverflow)?;
+        position_bin.reward_growth_checkpoints[reward_index] = bin.reward_growth_per_unit[reward_index];
+    }
+    Ok(())
+}
+
+/// Credits the active bin with the rewards emitted since the last update. Nothing is
+/// emitted while the active bin holds no liquidity; the period end moves back by that time
+/// instead, so those tokens are paid out later rather than stranded in the vault.
+///
+/// An active bin whose array is not among `bin_arrays` counts as empty when the bitmap marks
+/// the array empty. Otherwise the update is deferred, and the next update that sees the
+/// active bin's array credits the whole period to it. A swap can move the active bin in
+/// between, so deferred time goes to whichever bin is active at that next update.
+pub fn update_rewards(pool: &mut Pool, bin_arrays: &BinArrays, now: i64) -> Result<()> {
+    let mut bin = if bin_arrays.contains(pool.active_bin_id)? {
+        Some(bin_arrays.bin(pool.active_bin_id)?)
+    } else if bin_arrays.bitmap_covers(pool.active_bin_id)? && !bin_arrays.requires_array(pool.active_bin_id)? {
+        None
+    } else {
+        return Ok(());
+    };
+    accrue_rewards(&mut pool.reward_infos, bin.as_mut(), now)?;
+    match bin {
+        Some(bin) => bin_arrays.set_bin(pool.active_bin_id, bin),
+        None => Ok(()),
+    }
+}
+
+/// The emission half of `update_rewards`: credits `active_bin` with every reward emitted up
+/// to `now`, or carries the time forward when there is no bin or it holds no liquidity.
+pub fn accrue_rewards(reward_infos: &mut [RewardInfo], mut active_bin: Option<&mut Bin>, now: i64) -> Result<()> {
+    let liquidity_supply = active_bin.as_ref().map_or(0, |bin| bin.liquidity_supply);
+    for (reward_index, reward_info) in reward_infos.iter_mut().enumerate() {
+        if !reward_info.initialized() {
+            continue;
+        }
+        let emitted_until = now.min(reward_info.reward_duration_end);
+        if emitted_until > reward_info.last_update_time {
+            let elapsed = emitted_until.abs_diff(reward_info.last_update_time);
+            match active_bin.as_mut() {
+                Some(bin) if liquidity_supply > 0 => {
+                    let delta = bin_math::reward_growth_delta(reward_info.reward_rate, elapsed, liquidity_supply)?;
+                    let growth = &mut bin.reward_growth_per_unit[reward_index];
+                    *growth = growth.checked_add(delta).ok_or(MyError::MathOverflow)?;
+                }
+                _ => {
+                    // Carry the unemitted time past whatever is left of the period
+                    reward_info.reward_duration_end = reward_info
+                        .reward_duration_end
+                        .max(now)
+                        .checked_add(elapsed as i64)
+                        .ok_or(MyError::MathOverflow)?;
+                }
+            }
         }
+        reward_info.last_update_time = now;
     }
-    Ok((amount_a as u64, amount_b as u64))
+    Ok(())
 }
 
-pub fn calculate_claimable_amounts(
-    pool: &Account<Pool>,
-    position: &Account<Position>,
-    liquidity_to_remove: u128,
-) -> Result<(u128, u128)> {
-    let total_bins_in_pos = ((position.upper_bin_id - position.lower_bin_id) as u128
-        / pool.bin_step as u128)
-        .checked_add(1)
+/// Starts a new emission period of `reward_duration` seconds that pays out `amount` plus
+/// whatever the current period has left. Rewards must be updated up to `now` first.
+pub fn fund_reward(reward_info: &mut RewardInfo, amount: u64, now: i64) -> Result<()> {
+    reward_info.reward_rate = bin_math::reward_rate_after_funding(
+        reward_info.reward_rate,
+        reward_info.reward_duration_end,
+        now,
+        amount,
+        reward_info.reward_duration,
+    )?;
+    reward_info.reward_duration_end = now
+        .checked_add(reward_info.reward_duration as i64)
         .ok_or(MyError::MathOverflow)?;
-    if total_bins_in_pos == 0 {
-        return Ok((0, 0));
+    reward_info.last_update_time = now;
+    Ok(())
+}
+
+/// Adds `length_to_add` empty slots to the observation buffer, up to `MAX_ORACLE_LENGTH`.
+pub fn grow_oracle(oracle: &mut Oracle, length_to_add: u16) -> Result<()> {
+    let new_length = oracle.observations.len() + length_to_add as usize;
+    require!(length_to_add > 0 && new_length <= MAX_ORACLE_LENGTH, MyError::OracleLengthExceeded);
+    // Lay the buffer out oldest first so the new slots come straight after the newest observation
+    if oracle.length as usize == oracle.observations.len() {
+        let oldest_slot = (oracle.index as usize + 1) % oracle.observations.len();
+        oracle.observations.rotate_left(oldest_slot);
+        oracle.index = oracle.length - 1;
     }
+    oracle.observations.resize(new_length, Observation::default());
+    Ok(())
+}
 
-    let liquidity_per_bin = liquidity_to_remove
-        .checked_div(total_bins_in_pos)
+/// Appends an observation closing the period `active_bin_id` has been active for. Call
+/// before a swap moves the price; at most one observation is written per second.
+pub fn record_observation(oracle: &mut Oracle, active_bin_id: i32, now: i64) -> Result<()> {
+    let last = oracle.observations[oracle.index as usize];
+    if now <= last.timestamp {
+        return Ok(());
+    }
+    let cumulative_active_bin_id = (active_bin_id as i64)
+        .checked_mul(now - last.timestamp)
+        .and_then(|delta| last.cumulative_active_bin_id.checked_add(delta))
         .ok_or(MyError::MathOverflow)?;
+    let index = (oracle.index as usize + 1) % oracle.observations.len();
+    oracle.observations[index] = Observation {
+        timestamp: now,
+        cumulative_active_bin_id,
+    };
+    oracle.index = index as u16;
+    oracle.length = (oracle.length as usize + 1).min(oracle.observations.len()) as u16;
+    Ok(())
+}
 
-    let mut amount_a: u128 = 0;
-    let mut amount_b: u128 = 0;
+/// Cumulative active bin id at `target`, interpolated between observations or extrapolated
+/// past the newest one with the current `active_bin_id`.
+pub fn cumulative_active_bin_id_at(oracle: &Oracle, active_bin_id: i32, target: i64) -> Result<i64> {
+    let newest = oracle.observations[oracle.index as usize];
+    if target >= newest.timestamp {
+        return (active_bin_id as i64)
+            .checked_mul(target - newest.timestamp)
+            .and_then(|delta| newest.cumulative_active_bin_id.checked_add(delta))
+            .ok_or(MyError::MathOverflow.into());
+    }
 
-    for bin_id in (position.lower_bin_id..=position.upper_bin_id).step_by(pool.bin_step as usize) {
-        if bin_id > pool.active_bin_id {
-            amount_a = amount_a
-                .checked_add(liquidity_per_bin)
-                .ok_or(MyError::MathOverflow)?;
-        } else if bin_id < pool.active_bin_id {
-            let price = get_price_at_bin(bin_id, pool.bin_step)?;
-            let amount_b_in_bin = liquidity_per_bin
-                .checked_mul(price)
-                .ok_or(MyError::MathOverflow)?
-                .checked_div(PRECISION)
-                .ok_or(MyError::MathOverflow)?;
-            amount_b = amount_b
-                .checked_add(amount_b_in_bin)
-                .ok_or(MyError::MathOverflow)?;
+    let capacity = oracle.observations.len();
+    let count = oracle.length as usize;
+    let oldest_slot = if count < capacity { 0 } else { (oracle.index as usize + 1) % capacity };
+    let observation = |i: usize| oracle.observations[(oldest_slot + i) % capacity];
+    require!(observation(0).timestamp <= target, MyError::OracleObservationTooOld);
+
+    // Find the observations either side of `target`
+    let (mut before, mut after) = (0, count - 1);
+    while after - before > 1 {
+        let mid = (before + after) / 2;
+        if observation(mid).timestamp <= target {
+            before = mid;
         } else {
-            let price = get_price_at_bin(bin_id, pool.bin_step)?;
-            let amount_b_in_bin = liquidity_per_bin
-                .checked_mul(price)
-                .ok_or(MyError::MathOverflow)?
-                .checked_div(PRECISION)
-                .ok_or(MyError::MathOverflow)?;
-            amount_a = amount_a
-                .checked_add(liquidity_per_bin)
-                .ok_or(MyError::MathOverflow)?;
-            amount_b = amount_b
-                .checked_add(amount_b_in_bin)
-                .ok_or(MyError::MathOverflow)?;
+            after = mid;
         }
     }
+    let (before, after) = (observation(before), observation(after));
+    // The active bin held constant between two observations
+    let active_bin_id = (after.cumulative_active_bin_id - before.cumulative_active_bin_id)
+        / (after.timestamp - before.timestamp);
+    active_bin_id
+        .checked_mul(target - before.timestamp)
+        .and_then(|delta| before.cumulative_active_bin_id.checked_add(delta))
+        .ok_or(MyError::MathOverflow.into())
+}
+
+/// Adds `liquidity` shares backed by `amount_a`/`amount_b` to a bin.
+pub fn deposit_to_bin(bin: &mut Bin, amount_a: u128, amount_b: u128, liquidity: u128) -> Result<()> {
+    bin.amount_a = add_amount(bin.amount_a, amount_a)?;
+    bin.amount_b = add_amount(bin.amount_b, amount_b)?;
+    bin.liquidity_supply = bin
+        .liquidity_supply
+        .checked_add(liquidity)
+        .ok_or(MyError::MathOverflow)?;
+    Ok(())
+}
 
+/// Burns `liquidity` shares of a bin and takes the tokens they were worth out of it.
+pub fn withdraw_from_bin(bin: &mut Bin, liquidity: u128) -> Result<(u128, u128)> {
+    let (amount_a, amount_b) = bin_math::calculate_withdrawal_for_bin(&BinReserves::from(&*bin), liquidity)?;
+    bin.amount_a = sub_amount(bin.amount_a, amount_a)?;
+    bin.amount_b = sub_amount(bin.amount_b, amount_b)?;
+    bin.liquidity_supply = bin
+        .liquidity_supply
+        .checked_sub(liquidity)
+        .ok_or(MyError::MathOverflow)?;
     Ok((amount_a, amount_b))
 }
 
-pub fn calculate_accrued_fees(position: &Account<Position>, bin: &Bin) -> (u64, u64) {
-    let fee_growth_a = bin
-        .fee_growth_per_unit_a
-        .checked_sub(position.fee_growth_snapshot_a)
-        .unwrap_or(0);
-    let fee_growth_b = bin
-        .fee_growth_per_unit_b
-        .checked_sub(position.fee_growth_snapshot_b)
-        .unwrap_or(0);
-
-    let fees_a = fee_growth_a
-        .checked_mul(position.liquidity)
-        .unwrap_or(0)
-        .checked_div(PRECISION)
-        .unwrap_or(0) as u64;
-    let fees_b = fee_growth_b
-        .checked_mul(position.liquidity)
-        .unwrap_or(0)
-        .checked_div(PRECISION)
-        .unwrap_or(0) as u64;
+fn add_amount(amount: u64, delta: u128) -> Result<u64> {
+    u64::try_from(delta)
+        .ok()
+        .and_then(|delta| amount.checked_add(delta))
+        .ok_or(MyError::MathOverflow.into())
+}
 
-    (fees_a, fees_b)
+fn sub_amount(amount: u64, delta: u128) -> Result<u64> {
+    u64::try_from(delta)
+        .ok()
+        .and_then(|delta| amount.checked_sub(delta))
+        .ok_or(MyError::MathOverflow.into())
 }
 
-pub fn swap_a_to_b<'info>(
-    pool: &Account<'info, Pool>,
-    amount_in: u64,
-    bin_accounts: &'info [AccountInfo<'info>],
-    program_id: &Pubkey,
-) -> Result<(u64, i32)> {
-    let mut amount_remaining_in = amount_in as u128;
-    let mut total_amount_out: u128 = 0;
-    let mut current_bin_id = pool.active_bin_id;
-    let mut bin_accounts_iter = bin_accounts.iter();
-
-    while amount_remaining_in > 0 {
-        let bin_info = bin_accounts_iter
-            .next()
-            .ok_or(MyError::InsufficientLiquidityForSwap)?;
-
-        let (expected_pda, _) = Pubkey::find_program_address(
-            &[
-                b"bin",
-                pool.key().as_ref(),
-                &current_bin_id.to_le_bytes(),
-            ],
-            program_id,
-        );
-        require_keys_eq!(bin_info.key(), expected_pda, MyError::InvalidBinAccount);
+impl From<&Bin> for BinReserves {
+    fn from(bin: &Bin) -> Self {
+        BinReserves {
+            amount_a: bin.amount_a,
+            amount_b: bin.amount_b,
+            liquidity_supply: bin.liquidity_supply,
+        }
+    }
+}
+
+impl From<&Pool> for SwapParams {
+    fn from(pool: &Pool) -> Self {
+        SwapParams {
+            active_bin_id: pool.active_bin_id,
+            bin_step: pool.bin_step,
+            fee_rate: pool.fee_rate,
+            protocol_fee_bps: pool.protocol_fee_bps,
+            dynamic_fee: DynamicFeeParams {
+                filter_period: pool.filter_period,
+                decay_period: pool.decay_period,
+                reduction_factor: pool.reduction_factor,
+                variable_fee_control: pool.variable_fee_control,
+                max_volatility_accumulator: pool.max_volatility_accumulator,
+            },
+            volatility: VolatilityState {
+                volatility_accumulator: pool.volatility_accumulator,
+                volatility_reference: pool.volatility_reference,
+                index_reference: pool.index_reference,
+                last_update_timestamp: pool.last_update_timestamp,
+            },
+        }
+    }
+}
 
-        let bin_loader = AccountLoader::<'_, Bin>::try_from(bin_info)?;
-        let mut bin = bin_loader.load_mut()?;
+/// Swap parameters for a swap executed now, with the volatility references decayed for
+/// the time since the pool's last swap.
+fn current_swap_params(pool: &Pool) -> Result<SwapParams> {
+    let mut params = SwapParams::from(pool);
+    let now = Clock::get()?.unix_timestamp;
+    params
+        .volatility
+        .update_references(&params.dynamic_fee, params.active_bin_id, now)?;
+    Ok(params)
+}
 
-        let price = get_price_at_bin(current_bin_id, pool.bin_step)?;
-        let available_amount_b = bin
-            .liquidity
-            .checked_mul(price)
-            .ok_or(MyError::MathOverflow)?
-            .checked_div(PRECISION)
-            .ok_or(MyError::MathOverflow)?;
+/// Bin arrays passed in `remaining_accounts`, checked to belong to the pool, together with
+/// the pool's liquidity bitmap. Bins are read and written back by value so no borrow of the
+/// account data outlives a single access.
+pub struct BinArrays<'info> {
+    bin_step: u16,
+    bitmap: [u64; BIN_ARRAY_BITMAP_WORDS],
+    bitmap_extension: Option<AccountLoader<'info, BinArrayBitmapExtension>>,
+    arrays: Vec<(i32, AccountLoader<'info, BinArray>)>,
+}
 
-        if available_amount_b > 0 {
-            let fee = amount_remaining_in
-                .checked_mul(pool.fee_rate as u128)
-                .ok_or(MyError::MathOverflow)?
-                .checked_div(BASIS_POINT_MAX)
-                .ok_or(MyError::MathOverflow)?;
-            let amount_in_after_fee = amount_remaining_in
-                .checked_sub(fee)
-                .ok_or(MyError::MathOverflow)?;
-
-            let amount_out_from_bin = std::cmp::min(
-                amount_in_after_fee
-                    .checked_mul(price)
-                    .ok_or(MyError::MathOverflow)?
-                    .checked_div(PRECISION)
-                    .ok_or(MyError::MathOverflow)?,
-                available_amount_b,
-            );
-
-            let amount_in_consumed = if amount_out_from_bin == available_amount_b {
-                // This logic seems incorrect, let's stick to the math
-                amount_out_from_bin
-                    .checked_mul(PRECISION)
-                    .ok_or(MyError::MathOverflow)?
-                    .checked_div(price)
-                    .ok_or(MyError::MathOverflow)?
-            } else {
-                amount_out_from_bin
-                    .checked_mul(PRECISION)
-                    .ok_or(MyError::MathOverflow)?
-                    .checked_div(price)
-                    .ok_or(MyError::MathOverflow)?
+impl<'info> BinArrays<'info> {
+    pub fn load(
+        pool: &Account<'info, Pool>,
+        bitmap_extension: Option<&AccountLoader<'info, BinArrayBitmapExtension>>,
+        bin_array_accounts: &'info [AccountInfo<'info>],
+    ) -> Result<Self> {
+        let mut arrays = Vec::with_capacity(bin_array_accounts.len());
+        for bin_array_info in bin_array_accounts.iter() {
+            let bin_array_loader = AccountLoader::<'info, BinArray>::try_from(bin_array_info)?;
+            let index = {
+                let bin_array = bin_array_loader.load()?;
+                require_keys_eq!(bin_array.pool, pool.key(), MyError::InvalidBinArray);
+                bin_array.index
             };
+            arrays.push((index, bin_array_loader));
+        }
+        Ok(BinArrays {
+            bin_step: pool.bin_step,
+            bitmap: pool.bin_array_bitmap,
+            bitmap_extension: bitmap_extension.cloned(),
+            arrays,
+        })
+    }
 
-             let actual_amount_in_with_fee = amount_in_consumed
-                .checked_mul(BASIS_POINT_MAX)
-                .ok_or(MyError::MathOverflow)?
-                .checked_div(BASIS_POINT_MAX.checked_sub(pool.fee_rate as u128).ok_or(MyError::MathOverflow)?)
-                .ok_or(MyError::MathOverflow)?;
-
-
-            if bin.liquidity > 0 {
-                let fee_to_add = actual_amount_in_with_fee.checked_sub(amount_in_consumed).ok_or(MyError::MathOverflow)?;
-                let fee_growth_update = fee_to_add
-                    .checked_mul(PRECISION)
-                    .ok_or(MyError::MathOverflow)?
-                    .checked_div(bin.liquidity)
-                    .ok_or(MyError::MathOverflow)?;
-                bin.fee_growth_per_unit_b = bin
-                    .fee_growth_per_unit_b
-                    .checked_add(fee_growth_update)
-                    .ok_or(MyError::MathOverflow)?;
+    fn array(&self, index: i32) -> Option<&AccountLoader<'info, BinArray>> {
+        self.arrays
+            .iter()
+            .find(|(array_index, _)| *array_index == index)
+            .map(|(_, bin_array_loader)| bin_array_loader)
+    }
+
+    fn locate(&self, bin_id: i32) -> Result<Option<(&AccountLoader<'info, BinArray>, usize)>> {
+        let (index, slot) = bin_math::bin_array_location(bin_id, self.bin_step)?;
+        Ok(self.array(index).map(|bin_array_loader| (bin_array_loader, slot)))
+    }
+
+    fn with_bitmap<R>(&self, f: impl FnOnce(&BinArrayBitmap) -> R) -> Result<R> {
+        let extension = self.bitmap_extension.as_ref().map(|loader| loader.load()).transpose()?;
+        Ok(f(&BinArrayBitmap {
+            pool: &self.bitmap,
+            extension: extension
+                .as_ref()
+                .map(|extension| (&extension.positive_bin_array_bitmap[..], &extension.negative_bin_array_bitmap[..])),
+        }))
+    }
+
+    /// Whether the bitmap tracks the array holding `bin_id`: the pool's own range always is,
+    /// the extension's only when it was supplied.
+    pub fn bitmap_covers(&self, bin_id: i32) -> Result<bool> {
+        let (index, _) = bin_math::bin_array_location(bin_id, self.bin_step)?;
+        Ok(matches!(bin_math::bitmap_slot(index), Ok(BitmapSlot::Pool(_))) || self.bitmap_extension.is_some())
+    }
+
+    /// Whether the bitmap says the array holding `bin_id` has liquidity, so it must be supplied.
+    /// Arrays beyond the pool bitmap need the extension to answer.
+    pub fn requires_array(&self, bin_id: i32) -> Result<bool> {
+        let (index, _) = bin_math::bin_array_location(bin_id, self.bin_step)?;
+        Ok(self.with_bitmap(|bitmap| bitmap.has_liquidity(index))??)
+    }
+
+    /// The nearest bin past `bin_id` in the swap direction holding the token being bought. Supplied arrays
+    /// are scanned bin by bin; everything else is skipped a whole array at a time using the
+    /// bitmap, so empty arrays never need to be passed in.
+    pub fn next_bin_with_liquidity(&self, bin_id: i32, a_to_b: bool) -> Result<Option<i32>> {
+        let (mut index, slot) = bin_math::bin_array_location(bin_id, self.bin_step)?;
+        let mut slot = Some(slot);
+        loop {
+            if let Some(bin_array_loader) = self.array(index) {
+                let bin_array = bin_array_loader.load()?;
+                let has_amount_out = |slot: &usize| {
+                    BinReserves::from(&bin_array.bins[*slot]).amount_out_available(a_to_b) > 0
+                };
+                let found = if a_to_b {
+                    (0..slot.unwrap_or(BINS_PER_ARRAY)).rev().find(has_amount_out)
+                } else {
+                    (slot.map_or(0, |slot| slot + 1)..BINS_PER_ARRAY).find(has_amount_out)
+                };
+                if let Some(found) = found {
+                    return Ok(Some(bin_math::bin_id_at(index, found, self.bin_step)?));
+                }
+            } else if self.with_bitmap(|bitmap| bitmap.has_liquidity(index))?? {
+                // Skipping it could jump over liquidity the caller left out
+                return err!(MyError::MissingBinArray);
+            }
+            match self.with_bitmap(|bitmap| bitmap.next_with_liquidity(index, !a_to_b))?? {
+                Some(next_index) => {
+                    index = next_index;
+                    slot = None;
+                }
+                None => return Ok(None),
             }
+        }
+    }
 
-            bin.liquidity = bin
-                .liquidity
-                .checked_add(amount_in_consumed)
-                .ok_or(MyError::MathOverflow)?;
-            total_amount_out = total_amount_out
-                .checked_add(amount_out_from_bin)
-                .ok_or(MyError::MathOverflow)?;
-            amount_remaining_in = amount_remaining_in
-                .checked_sub(actual_amount_in_with_fee)
-                .ok_or(MyError::MathOverflow)?;
+    /// Brings the bitmap flags of every supplied array in line with its bins. Call once the
+    /// instruction has finished changing bin liquidity.
+    pub fn sync_bitmap(&self, pool: &mut Pool) -> Result<()> {
+        for (index, bin_array_loader) in self.arrays.iter() {
+            let has_liquidity = bin_array_loader.load()?.bins.iter().any(|bin| bin.liquidity_supply > 0);
+            let slot = bin_math::bitmap_slot(*index)?;
+            if let BitmapSlot::Pool(bit) = slot {
+                bin_math::set_bit(&mut pool.bin_array_bitmap, bit, has_liquidity);
+                continue;
+            }
+            let Some(bitmap_extension) = self.bitmap_extension.as_ref() else {
+                require!(!has_liquidity, MyError::MissingBitmapExtension);
+                continue;
+            };
+            let mut bitmap_extension = bitmap_extension.load_mut()?;
+            match slot {
+                BitmapSlot::Positive(bit) => bin_math::set_bit(&mut bitmap_extension.positive_bin_array_bitmap, bit, has_liquidity),
+                BitmapSlot::Negative(bit) => bin_math::set_bit(&mut bitmap_extension.negative_bin_array_bitmap, bit, has_liquidity),
+                BitmapSlot::Pool(_) => {}
+            }
         }
+        Ok(())
+    }
 
-        current_bin_id = current_bin_id
-            .checked_sub(pool.bin_step as i32)
-            .ok_or(MyError::MathOverflow)?;
+    pub fn contains(&self, bin_id: i32) -> Result<bool> {
+        Ok(self.locate(bin_id)?.is_some())
+    }
+
+    pub fn bin(&self, bin_id: i32) -> Result<Bin> {
+        let (bin_array_loader, slot) = self.locate(bin_id)?.ok_or(MyError::MissingBinArray)?;
+        let bin_array = bin_array_loader.load()?;
+        Ok(bin_array.bins[slot])
+    }
+
+    pub fn set_bin(&self, bin_id: i32, bin: Bin) -> Result<()> {
+        let (bin_array_loader, slot) = self.locate(bin_id)?.ok_or(MyError::MissingBinArray)?;
+        let mut bin_array = bin_array_loader.load_mut()?;
+        bin_array.bins[slot] = bin;
+        Ok(())
     }
-    
-    Ok((total_amount_out as u64, current_bin_id))
 }
 
-pub fn swap_b_to_a<'info>(
-    pool: &Account<'info, Pool>,
+/// Feeds bins from the supplied bin arrays to the swap walkers. Steps are only written
+/// back when `commit` is set.
+struct AccountBins<'a, 'info> {
+    bin_arrays: &'a BinArrays<'info>,
+    a_to_b: bool,
+    commit: bool,
+}
+
+impl SwapBins for AccountBins<'_, '_> {
+    type Error = Error;
+
+    fn reserves(&mut self, bin_id: i32) -> Result<BinReserves> {
+        if self.bin_arrays.contains(bin_id)? {
+            return Ok(BinReserves::from(&self.bin_arrays.bin(bin_id)?));
+        }
+        // Arrays the bitmap marks empty don't have to be passed in
+        require!(!self.bin_arrays.requires_array(bin_id)?, MyError::MissingBinArray);
+        Ok(BinReserves::default())
+    }
+
+    fn record(&mut self, bin_id: i32, step: &BinSwapStep) -> Result<()> {
+        if !self.commit {
+            return Ok(());
+        }
+        let mut bin = self.bin_arrays.bin(bin_id)?;
+        // The fee is paid in the input token
+        let fee_growth = if self.a_to_b {
+            &mut bin.fee_growth_per_unit_a
+        } else {
+            &mut bin.fee_growth_per_unit_b
+        };
+        *fee_growth = fee_growth
+            .checked_add(step.fee_growth_update)
+            .ok_or(MathError::Overflow)?;
+        bin.amount_a = step.amount_a_after;
+        bin.amount_b = step.amount_b_after;
+        self.bin_arrays.set_bin(bin_id, bin)
+    }
+
+    fn next_bin(&mut self, bin_id: i32, a_to_b: bool) -> Result<Option<i32>> {
+        self.bin_arrays.next_bin_with_liquidity(bin_id, a_to_b)
+    }
+}
+
+/// Executes (or, with `commit == false`, only simulates) an exact-input swap against
+/// `bin_arrays`.
+pub fn swap_exact_in(
+    pool: &Pool,
     amount_in: u64,
-    bin_accounts: &'info [AccountInfo<'info>],
-    program_id: &Pubkey,
-) -> Result<(u64, i32)> {
-    let mut amount_remaining_in = amount_in as u128;
-    let mut total_amount_out: u128 = 0;
-    let mut current_bin_id = pool.active_bin_id;
-    let mut bin_accounts_iter = bin_accounts.iter();
-
-    while amount_remaining_in > 0 {
-        let bin_info = bin_accounts_iter
-            .next()
-            .ok_or(MyError::InsufficientLiquidityForSwap)?;
-
-        let (expected_pda, _) = Pubkey::find_program_address(
-            &[
-                b"bin",
-                pool.key().as_ref(),
-                &current_bin_id.to_le_bytes(),
-            ],
-            program_id,
+    a_to_b: bool,
+    limit_bin_id: Option<i32>,
+    bin_arrays: &BinArrays,
+    commit: bool,
+) -> Result<SwapResult> {
+    let mut bins = AccountBins { bin_arrays, a_to_b, commit };
+    bin_math::swap_exact_in(&current_swap_params(pool)?, amount_in, a_to_b, limit_bin_id, &mut bins)
+}
+
+pub fn swap_exact_out(
+    pool: &Pool,
+    amount_out: u64,
+    a_to_b: bool,
+    bin_arrays: &BinArrays,
+) -> Result<SwapResult> {
+    let mut bins = AccountBins { bin_arrays, a_to_b, commit: true };
+    bin_math::swap_exact_out(&current_swap_params(pool)?, amount_out, a_to_b, &mut bins)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An oracle as `initialize_pool` creates it, grown to `capacity` slots.
+    fn oracle(capacity: u16, created_at: i64) -> Oracle {
+        let mut oracle = Oracle {
+            length: 1,
+            observations: vec![Observation { timestamp: created_at, cumulative_active_bin_id: 0 }],
+            ..Default::default()
+        };
+        if capacity > 1 {
+            grow_oracle(&mut oracle, capacity - 1).unwrap();
+        }
+        oracle
+    }
+
+    fn timestamps(oracle: &Oracle) -> Vec<i64> {
+        oracle.observations.iter().map(|observation| observation.timestamp).collect()
+    }
+
+    #[test]
+    fn observations_wrap_around_the_buffer() {
+        let mut oracle = oracle(3, 0);
+        record_observation(&mut oracle, 10, 10).unwrap();
+        // At most one observation per second
+        record_observation(&mut oracle, 99, 10).unwrap();
+        assert_eq!((oracle.index, oracle.length), (1, 2));
+
+        record_observation(&mut oracle, 20, 30).unwrap();
+        record_observation(&mut oracle, -5, 40).unwrap();
+        // The fourth observation overwrote the oldest
+        assert_eq!((oracle.index, oracle.length), (0, 3));
+        assert_eq!(timestamps(&oracle), [40, 10, 30]);
+        assert_eq!(oracle.observations[0].cumulative_active_bin_id, 10 * 10 + 20 * 20 + 10 * -5);
+        assert_eq!(
+            cumulative_active_bin_id_at(&oracle, 0, 5),
+            Err(MyError::OracleObservationTooOld.into())
         );
-        require_keys_eq!(bin_info.key(), expected_pda, MyError::InvalidBinAccount);
-
-        let bin_loader = AccountLoader::<'_, Bin>::try_from(bin_info)?;
-        let mut bin = bin_loader.load_mut()?;
-        
-        let available_amount_a = bin.liquidity;
-
-        if available_amount_a > 0 {
-            let price = get_price_at_bin(current_bin_id, pool.bin_step)?;
-            let fee = amount_remaining_in
-                .checked_mul(pool.fee_rate as u128)
-                .ok_or(MyError::MathOverflow)?
-                .checked_div(BASIS_POINT_MAX)
-                .ok_or(MyError::MathOverflow)?;
-            let amount_in_after_fee = amount_remaining_in
-                .checked_sub(fee)
-                .ok_or(MyError::MathOverflow)?;
-
-            let amount_out_from_bin = std::cmp::min(
-                amount_in_after_fee
-                    .checked_mul(PRECISION)
-                    .ok_or(MyError::MathOverflow)?
-                    .checked_div(price)
-                    .ok_or(MyError::MathOverflow)?,
-                available_amount_a,
-            );
-
-            let amount_in_consumed = amount_out_from_bin
-                .checked_mul(price)
-                .ok_or(MyError::MathOverflow)?
-                .checked_div(PRECISION)
-                .ok_or(MyError::MathOverflow)?;
-            
-            let actual_amount_in_with_fee = amount_in_consumed
-                .checked_mul(BASIS_POINT_MAX)
-                .ok_or(MyError::MathOverflow)?
-                .checked_div(BASIS_POINT_MAX.checked_sub(pool.fee_rate as u128).ok_or(MyError::MathOverflow)?)
-                .ok_or(MyError::MathOverflow)?;
-
-            if bin.liquidity > 0 {
-                 let fee_to_add = actual_amount_in_with_fee.checked_sub(amount_in_consumed).ok_or(MyError::MathOverflow)?;
-                let fee_growth_update = fee_to_add
-                    .checked_mul(PRECISION)
-                    .ok_or(MyError::MathOverflow)?
-                    .checked_div(bin.liquidity)
-                    .ok_or(MyError::MathOverflow)?;
-                bin.fee_growth_per_unit_a = bin
-                    .fee_growth_per_unit_a
-                    .checked_add(fee_growth_update)
-                    .ok_or(MyError::MathOverflow)?;
-            }
+    }
 
-            bin.liquidity = bin
-                .liquidity
-                .checked_sub(amount_out_from_bin) // <--- CORRECTED HERE
-                .ok_or(MyError::MathOverflow)?;
-            total_amount_out = total_amount_out
-                .checked_add(amount_out_from_bin)
-                .ok_or(MyError::MathOverflow)?;
-            amount_remaining_in = amount_remaining_in
-                .checked_sub(actual_amount_in_with_fee)
-                .ok_or(MyError::MathOverflow)?;
+    #[test]
+    fn growing_a_full_oracle_keeps_its_history_in_order() {
+        let mut oracle = oracle(3, 0);
+        for now in [10, 20, 30, 40] {
+            record_observation(&mut oracle, 1, now).unwrap();
         }
+        assert_eq!(timestamps(&oracle), [30, 40, 20]);
 
-        current_bin_id = current_bin_id
-            .checked_add(pool.bin_step as i32)
-            .ok_or(MyError::MathOverflow)?;
+        grow_oracle(&mut oracle, 2).unwrap();
+        assert_eq!(timestamps(&oracle), [20, 30, 40, 0, 0]);
+        assert_eq!((oracle.index, oracle.length), (2, 3));
+
+        // New observations fill the added slots before wrapping again
+        for now in [50, 60, 70] {
+            record_observation(&mut oracle, 1, now).unwrap();
+        }
+        assert_eq!(timestamps(&oracle), [70, 30, 40, 50, 60]);
+        assert_eq!(cumulative_active_bin_id_at(&oracle, 1, 35), Ok(35));
+
+        assert_eq!(
+            grow_oracle(&mut oracle, 0),
+            Err(MyError::OracleLengthExceeded.into())
+        );
+        assert_eq!(
+            grow_oracle(&mut oracle, MAX_ORACLE_LENGTH as u16),
+            Err(MyError::OracleLengthExceeded.into())
+        );
+    }
+
+    #[test]
+    fn cumulative_bin_id_interpolates_between_observations() {
+        let mut oracle = oracle(4, 100);
+        // Bin 10 from 100 to 110, bin -20 from 110 to 130
+        record_observation(&mut oracle, 10, 110).unwrap();
+        record_observation(&mut oracle, -20, 130).unwrap();
+
+        assert_eq!(cumulative_active_bin_id_at(&oracle, 7, 100), Ok(0));
+        assert_eq!(cumulative_active_bin_id_at(&oracle, 7, 105), Ok(50));
+        assert_eq!(cumulative_active_bin_id_at(&oracle, 7, 110), Ok(100));
+        assert_eq!(cumulative_active_bin_id_at(&oracle, 7, 120), Ok(100 - 200));
+        // Past the newest observation the current active bin carries on
+        assert_eq!(cumulative_active_bin_id_at(&oracle, 7, 140), Ok(100 - 400 + 70));
+        assert_eq!(
+            cumulative_active_bin_id_at(&oracle, 7, 99),
+            Err(MyError::OracleObservationTooOld.into())
+        );
     }
 
-    Ok((total_amount_out as u64, current_bin_id))
-}
\ No newline at end of file
+    /// A reward funded with `amount` over `duration` seconds at time 0.
+    fn reward(amount: u64, duration: u64) -> RewardInfo {
+        let mut reward_info = RewardInfo { mint: Pubkey::new_unique(), reward_duration: duration, ..Default::default() };
+        fund_reward(&mut reward_info, amount, 0).unwrap();
+        reward_info
+    }
+
+    fn bin(liquidity_supply: u128) -> Bin {
+        Bin { liquidity_supply, ..bytemuck::Zeroable::zeroed() }
+    }
+
+    #[test]
+    fn refunding_mid_period_rolls_the_remainder_into_the_new_rate() {
+        let mut reward_info = reward(1_000, 100);
+        assert_eq!(reward_info.reward_rate, 10 * PRECISION);
+
+        // 60 seconds' worth (600) is still to come and is spread with the new 500 over 100 seconds
+        fund_reward(&mut reward_info, 500, 40).unwrap();
+        assert_eq!(reward_info.reward_rate, 11 * PRECISION);
+        assert_eq!(reward_info.reward_duration_end, 140);
+        assert_eq!(reward_info.last_update_time, 40);
+
+        // Once the period is over nothing rolls over
+        fund_reward(&mut reward_info, 500, 200).unwrap();
+        assert_eq!(reward_info.reward_rate, 5 * PRECISION);
+        assert_eq!(reward_info.reward_duration_end, 300);
+    }
+
+    #[test]
+    fn emission_into_an_empty_bin_is_carried_forward() {
+        let mut reward_infos = [reward(1_000, 100), RewardInfo::default()];
+        let mut empty = bin(0);
+        accrue_rewards(&mut reward_infos, Some(&mut empty), 30).unwrap();
+        assert_eq!(empty.reward_growth_per_unit, [0, 0]);
+        // The 30 seconds nobody could earn are added to the end of the period
+        assert_eq!(reward_infos[0].reward_duration_end, 130);
+        assert_eq!(reward_infos[0].last_update_time, 30);
+
+        // The same applies when the bitmap shows the active bin's array is empty
+        accrue_rewards(&mut reward_infos, None, 50).unwrap();
+        assert_eq!(reward_infos[0].reward_duration_end, 150);
+
+        // All 1_000 tokens still reach liquidity, even if it arrives late
+        let mut active = bin(PRECISION);
+        accrue_rewards(&mut reward_infos, Some(&mut active), 1_000).unwrap();
+        assert_eq!(active.reward_growth_per_unit[0], 1_000);
+        assert_eq!(reward_infos[0].last_update_time, 1_000);
+        accrue_rewards(&mut reward_infos, Some(&mut active), 2_000).unwrap();
+        assert_eq!(active.reward_growth_per_unit[0], 1_000);
+    }
+
+    #[test]
+    fn deferred_accrual_credits_the_next_bin_seen() {
+        let mut reward_infos = [reward(1_000, 100), RewardInfo::default()];
+        let mut first = bin(PRECISION);
+        accrue_rewards(&mut reward_infos, Some(&mut first), 20).unwrap();
+        assert_eq!(first.reward_growth_per_unit[0], 200);
+
+        // An update that can't see the active bin's array leaves the reward untouched, so the
+        // next update covers the whole gap in whichever bin is then active
+        let mut second = bin(2 * PRECISION);
+        accrue_rewards(&mut reward_infos, Some(&mut second), 70).unwrap();
+        assert_eq!(first.reward_growth_per_unit[0], 200);
+        assert_eq!(second.reward_growth_per_unit[0], 250);
+        assert_eq!(reward_infos[0].reward_duration_end, 100);
+
+        // Emission stops at the end of the period
+        accrue_rewards(&mut reward_infos, Some(&mut second), 500).unwrap();
+        assert_eq!(second.reward_growth_per_unit[0], 250 + 150);
+    }
+}
@@ -1,5 +1,9 @@
 use anchor_lang::prelude::*;
 
+use anchor_lang::solana_program::entrypoint::MAX_PERMITTED_DATA_INCREASE;
+
+use crate::constants::{BINS_PER_ARRAY, BIN_ARRAY_BITMAP_WORDS, BITMAP_EXTENSION_WORDS, NUM_REWARDS};
+
 #[account]
 #[derive(Default)]
 pub struct Pool {
@@ -13,25 +17,202 @@ pub struct Pool {
     pub fee_rate: u16,
     pub reserves_a: u64,
     pub reserves_b: u64,
+    pub protocol_fee_bps: u16,
+    pub protocol_fees_a: u64,
+    pub protocol_fees_b: u64,
+    // Bit `index + BIN_ARRAY_BITMAP_WORDS * 32` is set while bin array `index` holds liquidity.
+    pub bin_array_bitmap: [u64; BIN_ARRAY_BITMAP_WORDS],
+    // Dynamic fee configuration, see `bin_math::DynamicFeeParams`.
+    pub filter_period: u16,
+    pub decay_period: u16,
+    pub reduction_factor: u16,
+    pub variable_fee_control: u32,
+    pub max_volatility_accumulator: u32,
+    // Dynamic fee state, see `bin_math::VolatilityState`.
+    pub volatility_accumulator: u32,
+    pub volatility_reference: u32,
+    pub index_reference: i32,
+    pub last_update_timestamp: i64,
+    // Liquidity mining rewards, emitted into whichever bin is active.
+    pub reward_infos: [RewardInfo; NUM_REWARDS],
 }
 
-#[account(zero_copy)]
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default)]
+pub struct RewardInfo {
+    // Default while the slot is unused.
+    pub mint: Pubkey,
+    pub vault: Pubkey,
+    pub funder: Pubkey,
+    pub reward_duration: u64,
+    // Tokens emitted per second, scaled by PRECISION.
+    pub reward_rate: u128,
+    pub reward_duration_end: i64,
+    pub last_update_time: i64,
+}
+
+impl RewardInfo {
+    pub fn initialized(&self) -> bool {
+        self.mint != Pubkey::default()
+    }
+}
+
+#[zero_copy]
 #[repr(C)]
 pub struct Bin {
-    pub liquidity: u128,
+    pub amount_a: u64,
+    pub amount_b: u64,
+    // Liquidity shares issued against the bin; positions hold part of this supply.
+    pub liquidity_supply: u128,
     pub fee_growth_per_unit_a: u128,
     pub fee_growth_per_unit_b: u128,
+    pub reward_growth_per_unit: [u128; NUM_REWARDS],
 }
 
-#[account]
-#[derive(Default)]
+#[account(zero_copy)]
+#[repr(C)]
+pub struct BinArray {
+    pub pool: Pubkey,
+    pub index: i32,
+    pub _padding: [u8; 12],
+    // Slot `i` holds the bin with id `(index * BINS_PER_ARRAY + i) * bin_step`.
+    pub bins: [Bin; BINS_PER_ARRAY],
+}
+
+// Liquidity flags for bin arrays beyond the pool bitmap. Bit `i` of the positive side is
+// the first array above the pool range plus `i`; the negative side counts down likewise.
+#[account(zero_copy)]
+#[repr(C)]
+pub struct BinArrayBitmapExtension {
+    pub pool: Pubkey,
+    pub positive_bin_array_bitmap: [u64; BITMAP_EXTENSION_WORDS],
+    pub negative_bin_array_bitmap: [u64; BITMAP_EXTENSION_WORDS],
+}
+
+#[account(zero_copy)]
+#[repr(C)]
 pub struct Position {
     pub pool: Pubkey,
+    // Wallet that opened the position. Authority follows the holder of `position_mint`.
     pub owner: Pubkey,
+    pub position_mint: Pubkey,
     pub lower_bin_id: i32,
     pub upper_bin_id: i32,
+    // Fees settled from bins whose share changed, waiting to be paid out.
+    pub fees_owed_a: u64,
+    pub fees_owed_b: u64,
+    // One of the `LIMIT_ORDER_*` constants; orders hold a single bin on one side of the price.
+    pub limit_order: u8,
+    // `PERMISSION_*` bits granted to `operator`.
+    pub operator_permissions: u8,
+    pub _padding: [u8; 6],
+    pub rewards_owed: [u64; NUM_REWARDS],
+    pub operator: Pubkey,
+    // NFT holder that approved `operator`; the approval lapses once the NFT changes hands.
+    pub operator_owner: Pubkey,
     pub liquidity: u128,
-    pub position_mint: Pubkey,
-    pub fee_growth_snapshot_a: u128,
-    pub fee_growth_snapshot_b: u128,
-}
\ No newline at end of file
+    // The account continues with one `PositionBin` per bin of the range, indexed by
+    // (bin_id - lower_bin_id) / bin_step; see `math::load_position_mut`.
+}
+
+impl Position {
+    /// Bins one instruction can add to a position account. Wider positions are opened with
+    /// this many and grown to their range with `increase_position_length`.
+    pub const BINS_PER_ALLOCATION: usize =
+        (MAX_PERMITTED_DATA_INCREASE - Position::space(0)) / std::mem::size_of::<PositionBin>();
+
+    /// Account size of a position holding `bin_count` bins.
+    pub const fn space(bin_count: usize) -> usize {
+        8 + std::mem::size_of::<Position>() + bin_count * std::mem::size_of::<PositionBin>()
+    }
+
+    /// Size `open_position` allocates for a range. Invalid ranges still get a size that can be
+    /// created; they are rejected once the position is initialized.
+    pub fn space_for_range(lower_bin_id: i32, upper_bin_id: i32, bin_step: u16) -> usize {
+        Position::space(range_bins(lower_bin_id, upper_bin_id, bin_step).min(Position::BINS_PER_ALLOCATION))
+    }
+
+    /// Size after one more `increase_position_length` step, capped at the position's range.
+    pub fn grown_space(&self, data_len: usize, bin_step: u16) -> usize {
+        let allocated = data_len.saturating_sub(Position::space(0)) / std::mem::size_of::<PositionBin>();
+        let target = range_bins(self.lower_bin_id, self.upper_bin_id, bin_step);
+        Position::space(target.min(allocated + Position::BINS_PER_ALLOCATION).max(allocated))
+    }
+}
+
+fn range_bins(lower_bin_id: i32, upper_bin_id: i32, bin_step: u16) -> usize {
+    let span = upper_bin_id.saturating_sub(lower_bin_id).max(0) / bin_step.max(1) as i32;
+    span as usize + 1
+}
+
+#[zero_copy]
+#[repr(C)]
+pub struct PositionBin {
+    pub liquidity_share: u128,
+    pub fee_growth_checkpoint_a: u128,
+    pub fee_growth_checkpoint_b: u128,
+    pub reward_growth_checkpoints: [u128; NUM_REWARDS],
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FeeTier {
+    pub bin_step: u16,
+    pub fee_rate: u16,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default)]
+pub struct SwapQuote {
+    pub amount_in: u64,
+    pub amount_out: u64,
+    pub fee: u64,
+    pub protocol_fee: u64,
+    pub bins_crossed: u32,
+    pub final_active_bin_id: i32,
+}
+
+// How `add_liquidity_by_strategy` spreads token amounts over a range of bins.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq)]
+pub enum StrategyType {
+    // Equal liquidity in every bin.
+    Spot,
+    // Most liquidity in the active bin, tapering off towards the edges of the range.
+    Curve,
+    // Least liquidity in the active bin, growing towards the edges of the range.
+    BidAsk,
+}
+
+// One entry of an `add_liquidity_by_weight` distribution.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default)]
+pub struct BinWeight {
+    // Offset from the active bin, in bins.
+    pub delta_id: i32,
+    pub weight_bps: u16,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default)]
+pub struct Observation {
+    pub timestamp: i64,
+    // Sum of active_bin_id * seconds since the oracle was created.
+    pub cumulative_active_bin_id: i64,
+}
+
+// Ring buffer of observations. Until it fills, slots `0..length` are in time order;
+// afterwards the oldest observation is the one after `index`.
+#[account]
+#[derive(Default)]
+pub struct Oracle {
+    pub bump: u8,
+    pub pool: Pubkey,
+    // Slot of the newest observation.
+    pub index: u16,
+    // Number of slots holding an observation.
+    pub length: u16,
+    pub observations: Vec<Observation>,
+}
+
+#[account]
+#[derive(Default)]
+pub struct GlobalConfig {
+    pub bump: u8,
+    pub admin: Pubkey,
+    pub fee_tiers: Vec<FeeTier>,
+}
@@ -14,10 +14,11 @@ import {
   ASSOCIATED_TOKEN_PROGRAM_ID,
   TOKEN_2022_PROGRAM_ID,
   getOrCreateAssociatedTokenAccount,
-  getAssociatedTokenAddress, // <<< FIX: Import the correct function
+  getAssociatedTokenAddress,
   mintTo,
   getAccount,
   createMint,
+  transfer,
 } from "@solana/spl-token";
 import { assert } from "chai";
 
@@ -25,6 +26,30 @@ const METADATA_PROGRAM_ID = new PublicKey(
   "metaqbxxUerdq28cj1RbAWkYQm3ybzjb6a8bt518x1s"
 );
 
+const BPF_LOADER_UPGRADEABLE_PROGRAM_ID = new PublicKey(
+  "BPFLoaderUpgradeab1e11111111111111111111111"
+);
+
+// Must match BINS_PER_ARRAY in the program
+const BINS_PER_ARRAY = 70;
+
+const i32ToLeBuffer = (value: number): Buffer => {
+  const buffer = Buffer.alloc(4);
+  buffer.writeInt32LE(value);
+  return buffer;
+};
+
+// Runs `call` and checks it fails with the program error `code`
+const expectError = async (call: Promise<unknown>, code: string) => {
+  try {
+    await call;
+  } catch (error) {
+    assert.equal((error as AnchorError).error?.errorCode.code, code);
+    return;
+  }
+  assert.fail(`expected the call to fail with ${code}`);
+};
+
 const sortMints = (
   mintA: PublicKey,
   mintB: PublicKey
@@ -45,6 +70,11 @@ describe("dloom_flow", () => {
   let t22MintA: PublicKey;
   let t22MintB: PublicKey;
 
+  const [globalConfigPda] = PublicKey.findProgramAddressSync(
+    [Buffer.from("global_config")],
+    program.programId
+  );
+
   before(async () => {
     const balance = await connection.getBalance(payer.publicKey);
     if (balance < 2 * LAMPORTS_PER_SOL) {
@@ -93,6 +123,88 @@ describe("dloom_flow", () => {
       TOKEN_2022_PROGRAM_ID
     );
     console.log("Test mints created.");
+
+    // Only the program's upgrade authority may create the config; it seeds the default fee tiers
+    const [programData] = PublicKey.findProgramAddressSync(
+      [program.programId.toBuffer()],
+      BPF_LOADER_UPGRADEABLE_PROGRAM_ID
+    );
+    try {
+      await program.methods
+        .initializeGlobalConfig()
+        .accountsPartial({
+          authority: payer.publicKey,
+          globalConfig: globalConfigPda,
+          program: program.programId,
+          programData,
+          systemProgram: SystemProgram.programId,
+        })
+        .rpc({ commitment: "confirmed" });
+    } catch (error) {
+      if (!(error as Error).message.includes("already in use")) {
+        throw error;
+      }
+    }
+  });
+
+  describe("Global config", () => {
+    const configAccounts = (admin: Keypair) => ({
+      admin: admin.publicKey,
+      globalConfig: globalConfigPda,
+    });
+
+    it("Should add and remove fee tiers", async () => {
+      const hasTier = async (binStep: number, feeRate: number) =>
+        (await program.account.globalConfig.fetch(globalConfigPda)).feeTiers.some(
+          (tier) => tier.binStep === binStep && tier.feeRate === feeRate
+        );
+
+      await program.methods
+        .addFeeTier(3, 15)
+        .accountsPartial(configAccounts(payer.payer))
+        .rpc({ commitment: "confirmed" });
+      assert.ok(await hasTier(3, 15));
+      await expectError(
+        program.methods
+          .addFeeTier(3, 15)
+          .accountsPartial(configAccounts(payer.payer))
+          .rpc({ commitment: "confirmed" }),
+        "FeeTierAlreadyExists"
+      );
+
+      await program.methods
+        .removeFeeTier(3, 15)
+        .accountsPartial(configAccounts(payer.payer))
+        .rpc({ commitment: "confirmed" });
+      assert.ok(!(await hasTier(3, 15)));
+      await expectError(
+        program.methods
+          .removeFeeTier(3, 15)
+          .accountsPartial(configAccounts(payer.payer))
+          .rpc({ commitment: "confirmed" }),
+        "FeeTierNotFound"
+      );
+    });
+
+    it("Should reject fee tiers above MAX_FEE_RATE or from non-admins", async () => {
+      // MAX_FEE_RATE is 1000 bps
+      await expectError(
+        program.methods
+          .addFeeTier(3, 1001)
+          .accountsPartial(configAccounts(payer.payer))
+          .rpc({ commitment: "confirmed" }),
+        "InvalidFeeRate"
+      );
+      const stranger = Keypair.generate();
+      await expectError(
+        program.methods
+          .addFeeTier(3, 15)
+          .accountsPartial(configAccounts(stranger))
+          .signers([stranger])
+          .rpc({ commitment: "confirmed" }),
+        "NotAdmin"
+      );
+    });
   });
 
   const testPoolFunctionality = (
@@ -105,17 +217,246 @@ describe("dloom_flow", () => {
     describe(description, () => {
       const binStep = 20;
       const feeRate = 50;
-      const initialBinId = 10;
+      // Bins sit on multiples of the bin step
+      const initialBinId = 2 * binStep;
 
       let tokenAMint: PublicKey, tokenBMint: PublicKey;
       let tokenAProgram: PublicKey, tokenBProgram: PublicKey;
       let poolPda: PublicKey;
+      let oraclePda: PublicKey;
       let tokenAVault: PublicKey, tokenBVault: PublicKey;
       let userTokenAAccount: PublicKey, userTokenBAccount: PublicKey;
 
       const positionMint = Keypair.generate();
       let positionPda: PublicKey;
 
+      const binArrayPda = (index: number): PublicKey =>
+        PublicKey.findProgramAddressSync(
+          [Buffer.from("bin_array"), poolPda.toBuffer(), i32ToLeBuffer(index)],
+          program.programId
+        )[0];
+
+      // Creates any missing bin arrays covering the range and returns them as remaining accounts
+      const binArraysFor = async (lowerBinId: number, upperBinId: number) => {
+        const lowerIndex = Math.floor(lowerBinId / binStep / BINS_PER_ARRAY);
+        const upperIndex = Math.floor(upperBinId / binStep / BINS_PER_ARRAY);
+        const accounts = [];
+        for (let index = lowerIndex; index <= upperIndex; index++) {
+          const binArray = binArrayPda(index);
+          if ((await connection.getAccountInfo(binArray)) === null) {
+            await program.methods
+              .initializeBinArray(index)
+              .accountsPartial({
+                binArray,
+                pool: poolPda,
+                payer: payer.publicKey,
+                systemProgram: SystemProgram.programId,
+              })
+              .rpc({ commitment: "confirmed" });
+          }
+          accounts.push({ pubkey: binArray, isWritable: true, isSigner: false });
+        }
+        return accounts;
+      };
+
+      const openPosition = async (
+        mint: Keypair,
+        lowerBinId: number,
+        upperBinId: number
+      ): Promise<PublicKey> => {
+        const [position] = PublicKey.findProgramAddressSync(
+          [Buffer.from("position"), mint.publicKey.toBuffer()],
+          program.programId
+        );
+        const [metadataAccount] = PublicKey.findProgramAddressSync(
+          [
+            Buffer.from("metadata"),
+            METADATA_PROGRAM_ID.toBuffer(),
+            mint.publicKey.toBuffer(),
+          ],
+          METADATA_PROGRAM_ID
+        );
+        const [masterEditionAccount] = PublicKey.findProgramAddressSync(
+          [
+            Buffer.from("metadata"),
+            METADATA_PROGRAM_ID.toBuffer(),
+            mint.publicKey.toBuffer(),
+            Buffer.from("edition"),
+          ],
+          METADATA_PROGRAM_ID
+        );
+        await program.methods
+          .openPosition(lowerBinId, upperBinId)
+          .accountsPartial({
+            pool: poolPda,
+            position,
+            owner: payer.publicKey,
+            positionMint: mint.publicKey,
+            userPositionNftAccount: await getAssociatedTokenAddress(
+              mint.publicKey,
+              payer.publicKey
+            ),
+            tokenAMint,
+            tokenBMint,
+            metadataAccount,
+            masterEditionAccount,
+            systemProgram: SystemProgram.programId,
+            tokenProgram: TOKEN_PROGRAM_ID,
+            associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+            tokenMetadataProgram: METADATA_PROGRAM_ID,
+            rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+          })
+          .signers([mint])
+          .rpc({ commitment: "confirmed" });
+        return position;
+      };
+
+      const positionAccounts = async (mint: Keypair, position: PublicKey) => ({
+        pool: poolPda,
+        position,
+        userPositionNftAccount: await getAssociatedTokenAddress(
+          mint.publicKey,
+          payer.publicKey
+        ),
+        bitmapExtension: null,
+        owner: payer.publicKey,
+        tokenAMint,
+        tokenBMint,
+        userTokenAAccount,
+        userTokenBAccount,
+        tokenAVault,
+        tokenBVault,
+        tokenAProgram,
+        tokenBProgram,
+      });
+
+      const addLiquidity = async (
+        mint: Keypair,
+        position: PublicKey,
+        lowerBinId: number,
+        upperBinId: number,
+        liquidityPerBin: BN
+      ) => {
+        const activeBinId = (await program.account.pool.fetch(poolPda))
+          .activeBinId;
+        await program.methods
+          .addLiquidity(
+            lowerBinId,
+            upperBinId,
+            liquidityPerBin,
+            new BN(1_000_000_000),
+            new BN(1_000_000_000),
+            activeBinId,
+            0
+          )
+          .accountsPartial(await positionAccounts(mint, position))
+          .remainingAccounts(await binArraysFor(lowerBinId, upperBinId))
+          .rpc({ commitment: "confirmed" });
+      };
+
+      const swap = async (
+        amountIn: BN,
+        aToB: boolean,
+        lowerBinId: number,
+        upperBinId: number
+      ) => {
+        await program.methods
+          .swap(amountIn, new BN(1), null)
+          .accountsPartial({
+            trader: payer.publicKey,
+            pool: poolPda,
+            bitmapExtension: null,
+            oracle: oraclePda,
+            tokenAMint,
+            tokenBMint,
+            sourceVault: aToB ? tokenAVault : tokenBVault,
+            destinationVault: aToB ? tokenBVault : tokenAVault,
+            userSourceTokenAccount: aToB ? userTokenAAccount : userTokenBAccount,
+            userDestinationTokenAccount: aToB
+              ? userTokenBAccount
+              : userTokenAAccount,
+            tokenAProgram,
+            tokenBProgram,
+          })
+          .remainingAccounts(await binArraysFor(lowerBinId, upperBinId))
+          .rpc({ commitment: "confirmed" });
+      };
+
+      // Opens a position around the initial bin and adds liquidity to it
+      const openFundedPosition = async () => {
+        const lowerBinId = initialBinId - 2 * binStep;
+        const upperBinId = initialBinId + 2 * binStep;
+        const mint = Keypair.generate();
+        const position = await openPosition(mint, lowerBinId, upperBinId);
+        await addLiquidity(
+          mint,
+          position,
+          lowerBinId,
+          upperBinId,
+          new BN(500_000)
+        );
+        return { mint, position, lowerBinId, upperBinId };
+      };
+
+      const tokenBalances = async (): Promise<[bigint, bigint]> => [
+        (
+          await getAccount(
+            connection,
+            userTokenAAccount,
+            "confirmed",
+            tokenAProgram
+          )
+        ).amount,
+        (
+          await getAccount(
+            connection,
+            userTokenBAccount,
+            "confirmed",
+            tokenBProgram
+          )
+        ).amount,
+      ];
+
+      // Every bin's inventory, plus fees not yet paid out, must still sit in the vaults
+      const assertVaultsCoverBins = async () => {
+        const binArrays = await program.account.binArray.all([
+          { memcmp: { offset: 8, bytes: poolPda.toBase58() } },
+        ]);
+        let binsA = BigInt(0);
+        let binsB = BigInt(0);
+        for (const binArray of binArrays) {
+          for (const bin of binArray.account.bins) {
+            binsA += BigInt(bin.amountA.toString());
+            binsB += BigInt(bin.amountB.toString());
+          }
+        }
+        const pool = await program.account.pool.fetch(poolPda, "confirmed");
+        const vaultA = await getAccount(
+          connection,
+          tokenAVault,
+          "confirmed",
+          tokenAProgram
+        );
+        const vaultB = await getAccount(
+          connection,
+          tokenBVault,
+          "confirmed",
+          tokenBProgram
+        );
+        assert.ok(BigInt(pool.reservesA.toString()) >= binsA);
+        assert.ok(BigInt(pool.reservesB.toString()) >= binsB);
+        assert.ok(
+          vaultA.amount >=
+            BigInt(pool.reservesA.toString()) +
+              BigInt(pool.protocolFeesA.toString())
+        );
+        assert.ok(
+          vaultB.amount >=
+            BigInt(pool.reservesB.toString()) +
+              BigInt(pool.protocolFeesB.toString())
+        );
+      };
+
       before(async () => {
         const sorted = sortMints(mint1(), mint2());
         tokenAMint = sorted[0];
@@ -145,9 +486,8 @@ describe("dloom_flow", () => {
           [Buffer.from("vault"), poolPda.toBuffer(), tokenBMint.toBuffer()],
           program.programId
         );
-
-        [positionPda] = PublicKey.findProgramAddressSync(
-          [Buffer.from("position"), positionMint.publicKey.toBuffer()],
+        [oraclePda] = PublicKey.findProgramAddressSync(
+          [Buffer.from("oracle"), poolPda.toBuffer()],
           program.programId
         );
 
@@ -202,7 +542,8 @@ describe("dloom_flow", () => {
         try {
           await program.methods
             .initializePool(binStep, feeRate, initialBinId)
-            .accounts({
+            .accountsPartial({
+              globalConfig: globalConfigPda,
               tokenAMint,
               tokenBMint,
               payer: payer.publicKey,
@@ -211,6 +552,7 @@ describe("dloom_flow", () => {
               tokenBProgram,
               tokenAVault,
               tokenBVault,
+              oracle: oraclePda,
             })
             .rpc({ commitment: "confirmed" });
 
@@ -232,94 +574,23 @@ describe("dloom_flow", () => {
       it("Should add liquidity", async () => {
         const lowerBinId = initialBinId - 2 * binStep;
         const upperBinId = initialBinId + 2 * binStep;
-        const amountToDeposit = new BN(100_000);
-
-        const binPubkeys: PublicKey[] = [];
-        for (let id = lowerBinId; id <= upperBinId; id += binStep) {
-          const [binPda] = PublicKey.findProgramAddressSync(
-            [
-              Buffer.from("bin"),
-              poolPda.toBuffer(),
-              new BN(id).toBuffer("le", 4),
-            ],
-            program.programId
-          );
-          binPubkeys.push(binPda);
-          try {
-            await program.methods
-              .initializeBin(id)
-              .accounts({ bin: binPda, pool: poolPda, payer: payer.publicKey })
-              .rpc();
-          } catch (e) {
-            /* ignore */
-          }
-        }
-
-        const remainingAccounts = binPubkeys.map((pubkey) => ({
-          pubkey,
-          isWritable: true,
-          isSigner: false,
-        }));
-
-        // <<< FIX: Use getAssociatedTokenAddress to just get the address, not create it.
-        const userPositionNftAccount = await getAssociatedTokenAddress(
-          positionMint.publicKey,
-          payer.publicKey
-        );
+        const liquidityPerBin = new BN(100_000);
 
-        const [metadataPda] = PublicKey.findProgramAddressSync(
-          [
-            Buffer.from("metadata"),
-            METADATA_PROGRAM_ID.toBuffer(),
-            positionMint.publicKey.toBuffer(),
-          ],
-          METADATA_PROGRAM_ID
-        );
-        const [masterEditionPda] = PublicKey.findProgramAddressSync(
-          [
-            Buffer.from("metadata"),
-            METADATA_PROGRAM_ID.toBuffer(),
-            positionMint.publicKey.toBuffer(),
-            Buffer.from("edition"),
-          ],
-          METADATA_PROGRAM_ID
+        positionPda = await openPosition(positionMint, lowerBinId, upperBinId);
+        await addLiquidity(
+          positionMint,
+          positionPda,
+          lowerBinId,
+          upperBinId,
+          liquidityPerBin
         );
 
-        await program.methods
-          .addLiquidity(lowerBinId, upperBinId, amountToDeposit)
-          .accounts({
-            pool: poolPda,
-            position: positionPda,
-            owner: payer.publicKey,
-            payer: payer.publicKey,
-            tokenAMint,
-            tokenBMint,
-            tokenAVault,
-            tokenBVault,
-            positionMint: positionMint.publicKey,
-            userPositionNftAccount,
-            metadataAccount: metadataPda,
-            masterEditionAccount: masterEditionPda,
-            userTokenAAccount,
-            userTokenBAccount,
-            tokenAProgram,
-            tokenBProgram,
-            tokenProgram: TOKEN_PROGRAM_ID,
-            tokenMetadataProgram: METADATA_PROGRAM_ID,
-            associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
-            systemProgram: SystemProgram.programId,
-            rent: anchor.web3.SYSVAR_RENT_PUBKEY, // Add rent sysvar
-          })
-          .remainingAccounts(remainingAccounts)
-          .signers([positionMint])
-          .rpc({ commitment: "confirmed" });
-
         const positionAccount = await program.account.position.fetch(
           positionPda
         );
         assert.equal(
           positionAccount.liquidity.toString(),
-          amountToDeposit.toString()
+          liquidityPerBin.muln(5).toString()
         );
       });
 
@@ -328,44 +599,16 @@ describe("dloom_flow", () => {
           positionPda
         );
         const liquidityToRemove = positionAccountBefore.liquidity;
-        const lowerBinId = positionAccountBefore.lowerBinId;
-        const upperBinId = positionAccountBefore.upperBinId;
-
-        const binPubkeys: PublicKey[] = [];
-        for (let id = lowerBinId; id <= upperBinId; id += binStep) {
-          const [binPda] = PublicKey.findProgramAddressSync(
-            [
-              Buffer.from("bin"),
-              poolPda.toBuffer(),
-              new BN(id).toBuffer("le", 4),
-            ],
-            program.programId
-          );
-          binPubkeys.push(binPda);
-        }
-
-        const remainingAccounts = binPubkeys.map((pubkey) => ({
-          pubkey,
-          isWritable: true,
-          isSigner: false,
-        }));
 
         await program.methods
           .removeLiquidity(liquidityToRemove as BN, new BN(0), new BN(0))
-          .accounts({
-            owner: payer.publicKey,
-            pool: poolPda,
-            position: positionPda,
-            tokenAMint,
-            tokenBMint,
-            tokenAVault,
-            tokenBVault,
-            userTokenAAccount,
-            userTokenBAccount,
-            tokenAProgram,
-            tokenBProgram,
-          })
-          .remainingAccounts(remainingAccounts)
+          .accountsPartial(await positionAccounts(positionMint, positionPda))
+          .remainingAccounts(
+            await binArraysFor(
+              positionAccountBefore.lowerBinId,
+              positionAccountBefore.upperBinId
+            )
+          )
           .rpc({ commitment: "confirmed" });
 
         const positionAccountAfter = await program.account.position.fetch(
@@ -377,149 +620,483 @@ describe("dloom_flow", () => {
       it("Should swap tokens", async () => {
         const lowerBinId = initialBinId - 5 * binStep;
         const upperBinId = initialBinId + 5 * binStep;
-        const amountToDeposit = new BN(500_000);
         const tempPositionMint = Keypair.generate();
-        const [tempPositionPda] = PublicKey.findProgramAddressSync(
-          [Buffer.from("position"), tempPositionMint.publicKey.toBuffer()],
-          program.programId
+        const tempPositionPda = await openPosition(
+          tempPositionMint,
+          lowerBinId,
+          upperBinId
         );
-
-        // <<< FIX: Use getAssociatedTokenAddress here as well.
-        const tempUserPositionNftAccount = await getAssociatedTokenAddress(
-          tempPositionMint.publicKey,
-          payer.publicKey
+        await addLiquidity(
+          tempPositionMint,
+          tempPositionPda,
+          lowerBinId,
+          upperBinId,
+          new BN(500_000)
         );
 
-        const [tempMetadataPda] = PublicKey.findProgramAddressSync(
-          [
-            Buffer.from("metadata"),
-            METADATA_PROGRAM_ID.toBuffer(),
-            tempPositionMint.publicKey.toBuffer(),
-          ],
-          METADATA_PROGRAM_ID
-        );
-        const [tempMasterEditionPda] = PublicKey.findProgramAddressSync(
-          [
-            Buffer.from("metadata"),
-            METADATA_PROGRAM_ID.toBuffer(),
-            tempPositionMint.publicKey.toBuffer(),
-            Buffer.from("edition"),
-          ],
-          METADATA_PROGRAM_ID
+        const amountIn = new BN(10000);
+        const minAmountOut = new BN(1);
+        const balanceBefore = await getAccount(
+          connection,
+          userTokenBAccount,
+          "confirmed",
+          tokenBProgram
         );
 
-        const binPubkeys: PublicKey[] = [];
-        for (let id = lowerBinId; id <= upperBinId; id += binStep) {
-          const [binPda] = PublicKey.findProgramAddressSync(
-            [
-              Buffer.from("bin"),
-              poolPda.toBuffer(),
-              new BN(id).toBuffer("le", 4),
-            ],
-            program.programId
-          );
-          binPubkeys.push(binPda);
-          try {
-            await program.methods
-              .initializeBin(id)
-              .accounts({ bin: binPda, pool: poolPda, payer: payer.publicKey })
-              .rpc();
-          } catch (e) {
-            /* ignore */
-          }
-        }
         await program.methods
-          .addLiquidity(lowerBinId, upperBinId, amountToDeposit)
-          .accounts({
+          .swap(amountIn, minAmountOut, null)
+          .accountsPartial({
+            trader: payer.publicKey,
             pool: poolPda,
-            position: tempPositionPda,
-            owner: payer.publicKey,
-            payer: payer.publicKey,
+            bitmapExtension: null,
+            oracle: oraclePda,
             tokenAMint,
             tokenBMint,
-            tokenAVault,
-            tokenBVault,
-            positionMint: tempPositionMint.publicKey,
-            userPositionNftAccount: tempUserPositionNftAccount,
-            metadataAccount: tempMetadataPda,
-            masterEditionAccount: tempMasterEditionPda,
-            userTokenAAccount,
-            userTokenBAccount,
+            sourceVault: tokenAVault,
+            destinationVault: tokenBVault,
+            userSourceTokenAccount: userTokenAAccount,
+            userDestinationTokenAccount: userTokenBAccount,
             tokenAProgram,
             tokenBProgram,
-            tokenProgram: TOKEN_PROGRAM_ID,
-            tokenMetadataProgram: METADATA_PROGRAM_ID,
-            associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
-            systemProgram: SystemProgram.programId,
-            rent: anchor.web3.SYSVAR_RENT_PUBKEY,
           })
-          .remainingAccounts(
-            binPubkeys.map((p) => ({
-              pubkey: p,
-              isWritable: true,
-              isSigner: false,
-            }))
-          )
-          .signers([tempPositionMint])
+          .remainingAccounts(await binArraysFor(lowerBinId, upperBinId))
           .rpc({ commitment: "confirmed" });
 
-        const amountIn = new BN(10000);
-        const minAmountOut = new BN(1);
-        const balanceBefore = await getAccount(
+        const balanceAfter = await getAccount(
           connection,
           userTokenBAccount,
           "confirmed",
           tokenBProgram
         );
+        assert.ok(
+          balanceAfter.amount > balanceBefore.amount,
+          "Destination balance should have increased after swap"
+        );
+      });
+
+      it("Should pay swap fees in the token they were charged in", async () => {
+        const lowerBinId = initialBinId - 5 * binStep;
+        const upperBinId = initialBinId + 5 * binStep;
+        const feeMint = Keypair.generate();
+        const feePda = await openPosition(feeMint, lowerBinId, upperBinId);
+        await addLiquidity(
+          feeMint,
+          feePda,
+          lowerBinId,
+          upperBinId,
+          new BN(500_000)
+        );
+
+        await swap(new BN(50_000), true, lowerBinId, upperBinId);
+        await swap(new BN(50_000), false, lowerBinId, upperBinId);
+        await assertVaultsCoverBins();
+
+        const balanceA = async () =>
+          (
+            await getAccount(
+              connection,
+              userTokenAAccount,
+              "confirmed",
+              tokenAProgram
+            )
+          ).amount;
+        const balanceB = async () =>
+          (
+            await getAccount(
+              connection,
+              userTokenBAccount,
+              "confirmed",
+              tokenBProgram
+            )
+          ).amount;
+        const balanceABefore = await balanceA();
+        const balanceBBefore = await balanceB();
+        await program.methods
+          .claimFees()
+          .accountsPartial(await positionAccounts(feeMint, feePda))
+          .remainingAccounts(await binArraysFor(lowerBinId, upperBinId))
+          .rpc({ commitment: "confirmed" });
+
+        // Both directions were traded, so both tokens earned fees
+        assert.ok((await balanceA()) > balanceABefore);
+        assert.ok((await balanceB()) > balanceBBefore);
+        const claimed = await program.account.position.fetch(feePda);
+        assert.equal(claimed.feesOwedA.toString(), "0");
+        assert.equal(claimed.feesOwedB.toString(), "0");
+        await assertVaultsCoverBins();
+      });
+
+      it("Should collect protocol fees for the admin", async () => {
+        const { lowerBinId, upperBinId } = await openFundedPosition();
+        const setProtocolFee = (protocolFeeBps: number) =>
+          program.methods
+            .setProtocolFee(protocolFeeBps)
+            .accountsPartial({
+              admin: payer.publicKey,
+              globalConfig: globalConfigPda,
+              pool: poolPda,
+            })
+            .rpc({ commitment: "confirmed" });
+        const collectProtocolFees = (admin: Keypair) =>
+          program.methods
+            .collectProtocolFees()
+            .accountsPartial({
+              admin: admin.publicKey,
+              globalConfig: globalConfigPda,
+              pool: poolPda,
+              tokenAMint,
+              tokenBMint,
+              tokenAVault,
+              tokenBVault,
+              treasuryTokenAAccount: userTokenAAccount,
+              treasuryTokenBAccount: userTokenBAccount,
+              tokenAProgram,
+              tokenBProgram,
+            })
+            .signers([admin])
+            .rpc({ commitment: "confirmed" });
+
+        await setProtocolFee(2000);
+        await swap(new BN(50_000), true, lowerBinId, upperBinId);
+        await setProtocolFee(0);
+        const owed = await program.account.pool.fetch(poolPda, "confirmed");
+        assert.ok(owed.protocolFeesA.gtn(0), "A to B swaps pay the protocol in A");
+
+        const stranger = Keypair.generate();
+        await expectError(collectProtocolFees(stranger), "NotAdmin");
+
+        const [balanceABefore, balanceBBefore] = await tokenBalances();
+        await collectProtocolFees(payer.payer);
+        const [balanceAAfter, balanceBAfter] = await tokenBalances();
+        assert.equal(
+          balanceAAfter - balanceABefore,
+          BigInt(owed.protocolFeesA.toString())
+        );
+        assert.equal(
+          balanceBAfter - balanceBBefore,
+          BigInt(owed.protocolFeesB.toString())
+        );
+        const collected = await program.account.pool.fetch(poolPda, "confirmed");
+        assert.equal(collected.protocolFeesA.toString(), "0");
+        assert.equal(collected.protocolFeesB.toString(), "0");
+        await assertVaultsCoverBins();
+      });
+
+      it("Should claim accrued fees once", async () => {
+        const { mint, position, lowerBinId, upperBinId } =
+          await openFundedPosition();
+        await swap(new BN(20_000), true, lowerBinId, upperBinId);
+
+        const claimFees = async () =>
+          program.methods
+            .claimFees()
+            .accountsPartial(await positionAccounts(mint, position))
+            .remainingAccounts(await binArraysFor(lowerBinId, upperBinId))
+            .rpc({ commitment: "confirmed" });
+
+        const [balanceABefore] = await tokenBalances();
+        await claimFees();
+        const [balanceAAfter, balanceBAfter] = await tokenBalances();
+        assert.ok(balanceAAfter > balanceABefore, "A to B swaps pay fees in A");
 
-        const activeId = (await program.account.pool.fetch(poolPda))
+        // Nothing accrued since, so a second claim pays nothing
+        await claimFees();
+        const [balanceAAgain, balanceBAgain] = await tokenBalances();
+        assert.equal(balanceAAgain, balanceAAfter);
+        assert.equal(balanceBAgain, balanceBAfter);
+      });
+
+      it("Should move control of a position with its NFT", async () => {
+        const { mint, position } = await openFundedPosition();
+        const newHolder = Keypair.generate();
+        const sourceNftAccount = await getAssociatedTokenAddress(
+          mint.publicKey,
+          payer.publicKey
+        );
+        const newHolderNftAccount = (
+          await getOrCreateAssociatedTokenAccount(
+            connection,
+            payer.payer,
+            mint.publicKey,
+            newHolder.publicKey,
+            false,
+            "confirmed"
+          )
+        ).address;
+        await transfer(
+          connection,
+          payer.payer,
+          sourceNftAccount,
+          newHolderNftAccount,
+          payer.payer,
+          1,
+          [],
+          { commitment: "confirmed" }
+        );
+
+        const approveOperator = (owner: Keypair, nftAccount: PublicKey) =>
+          program.methods
+            .approveOperator(Keypair.generate().publicKey, 1)
+            .accountsPartial({
+              owner: owner.publicKey,
+              position,
+              userPositionNftAccount: nftAccount,
+            })
+            .signers([owner])
+            .rpc({ commitment: "confirmed" });
+
+        // The wallet that opened the position no longer controls it
+        await expectError(
+          approveOperator(payer.payer, sourceNftAccount),
+          "Unauthorized"
+        );
+        await approveOperator(newHolder, newHolderNftAccount);
+        const moved = await program.account.position.fetch(position);
+        assert.ok(moved.operatorOwner.equals(newHolder.publicKey));
+        // `owner` still records who opened it
+        assert.ok(moved.owner.equals(payer.publicKey));
+      });
+
+      it("Should let an approved operator act within its permissions", async () => {
+        const { mint, position, lowerBinId, upperBinId } =
+          await openFundedPosition();
+        const operator = Keypair.generate();
+        const stranger = Keypair.generate();
+        const userPositionNftAccount = await getAssociatedTokenAddress(
+          mint.publicKey,
+          payer.publicKey
+        );
+        // Bit value of PERMISSION_CLAIM_FEES
+        const claimFeesPermission = 1;
+
+        const approveOperator = (owner: Keypair, permissions: number) =>
+          program.methods
+            .approveOperator(operator.publicKey, permissions)
+            .accountsPartial({
+              owner: owner.publicKey,
+              position,
+              userPositionNftAccount,
+            })
+            .signers([owner])
+            .rpc({ commitment: "confirmed" });
+        const claimFeesAsOperator = async () =>
+          program.methods
+            .claimFees()
+            .accountsPartial({
+              ...(await positionAccounts(mint, position)),
+              owner: operator.publicKey,
+            })
+            .remainingAccounts(await binArraysFor(lowerBinId, upperBinId))
+            .signers([operator])
+            .rpc({ commitment: "confirmed" });
+
+        // Only the holder may appoint an operator
+        await expectError(
+          approveOperator(stranger, claimFeesPermission),
+          "Unauthorized"
+        );
+        await expectError(
+          approveOperator(payer.payer, 1 << 7),
+          "InvalidOperatorPermissions"
+        );
+        await approveOperator(payer.payer, claimFeesPermission);
+
+        await swap(new BN(20_000), true, lowerBinId, upperBinId);
+        const [balanceABefore] = await tokenBalances();
+        await claimFeesAsOperator();
+        const [balanceAAfter] = await tokenBalances();
+        assert.ok(
+          balanceAAfter > balanceABefore,
+          "Fees claimed by the operator go to the holder"
+        );
+
+        // Claiming fees does not let the operator add liquidity
+        const activeBinId = (await program.account.pool.fetch(poolPda))
           .activeBinId;
-        const swapBinPubkeys: PublicKey[] = [];
-        for (let i = -5; i <= 5; i++) {
-          const id = activeId + i * binStep;
-          const [binPda] = PublicKey.findProgramAddressSync(
-            [
-              Buffer.from("bin"),
-              poolPda.toBuffer(),
-              new BN(id).toBuffer("le", 4),
-            ],
-            program.programId
+        await expectError(
+          program.methods
+            .addLiquidity(
+              lowerBinId,
+              upperBinId,
+              new BN(1_000),
+              new BN(1_000_000_000),
+              new BN(1_000_000_000),
+              activeBinId,
+              0
+            )
+            .accountsPartial({
+              ...(await positionAccounts(mint, position)),
+              owner: operator.publicKey,
+            })
+            .remainingAccounts(await binArraysFor(lowerBinId, upperBinId))
+            .signers([operator])
+            .rpc({ commitment: "confirmed" }),
+          "Unauthorized"
+        );
+
+        // Approving no permissions revokes the operator
+        await approveOperator(payer.payer, 0);
+        await expectError(claimFeesAsOperator(), "Unauthorized");
+      });
+
+      it("Should close a Token-2022 position mint when burning the position", async () => {
+        const mint = Keypair.generate();
+        const [position] = PublicKey.findProgramAddressSync(
+          [Buffer.from("position"), mint.publicKey.toBuffer()],
+          program.programId
+        );
+        const userPositionNftAccount = await getAssociatedTokenAddress(
+          mint.publicKey,
+          payer.publicKey,
+          false,
+          TOKEN_2022_PROGRAM_ID
+        );
+        await program.methods
+          .openPositionToken2022(initialBinId, initialBinId + binStep)
+          .accountsPartial({
+            pool: poolPda,
+            position,
+            owner: payer.publicKey,
+            positionMint: mint.publicKey,
+            userPositionNftAccount,
+            systemProgram: SystemProgram.programId,
+            tokenProgram: TOKEN_2022_PROGRAM_ID,
+            associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+          })
+          .signers([mint])
+          .rpc({ commitment: "confirmed" });
+
+        await program.methods
+          .burnEmptyPosition()
+          .accountsPartial({
+            owner: payer.publicKey,
+            pool: poolPda,
+            position,
+            positionMint: mint.publicKey,
+            userPositionNftAccount,
+            tokenProgram: TOKEN_2022_PROGRAM_ID,
+            metadataAccount: null,
+            masterEditionAccount: null,
+            tokenMetadataProgram: null,
+          })
+          .rpc({ commitment: "confirmed" });
+
+        for (const account of [position, mint.publicKey, userPositionNftAccount]) {
+          assert.isNull(await connection.getAccountInfo(account, "confirmed"));
+        }
+      });
+
+      it("Should grow a 500-bin position to cover its range", async () => {
+        const wideMint = Keypair.generate();
+        const lowerBinId = initialBinId - 250 * binStep;
+        const widePda = await openPosition(
+          wideMint,
+          lowerBinId,
+          lowerBinId + 499 * binStep
+        );
+        const dataLength = async () =>
+          (await connection.getAccountInfo(widePda, "confirmed"))!.data.length;
+
+        // Keep growing until a call no longer changes the account size
+        let length = await dataLength();
+        const opened = length;
+        for (;;) {
+          await program.methods
+            .increasePositionLength()
+            .accountsPartial({
+              pool: poolPda,
+              position: widePda,
+              payer: payer.publicKey,
+              systemProgram: SystemProgram.programId,
+            })
+            .rpc({ commitment: "confirmed" });
+          const grown = await dataLength();
+          if (grown === length) break;
+          length = grown;
+        }
+
+        const binEntrySize = 80;
+        assert.ok(opened < length, "Opening allocates only part of the range");
+        assert.equal((length - opened) % binEntrySize, 0);
+        const position = await program.account.position.fetch(widePda);
+        assert.equal(position.lowerBinId, lowerBinId);
+        assert.equal(position.upperBinId, lowerBinId + 499 * binStep);
+      });
+
+      it("Should place a limit order and claim it once filled", async () => {
+        const activeBinId = (await program.account.pool.fetch(poolPda))
+          .activeBinId;
+        const orderBinId = activeBinId + binStep;
+        const orderMint = Keypair.generate();
+        const orderPda = await openPosition(orderMint, orderBinId, orderBinId);
+
+        await program.methods
+          .placeLimitOrder(orderBinId, new BN(100_000), true)
+          .accountsPartial(await positionAccounts(orderMint, orderPda))
+          .remainingAccounts(await binArraysFor(orderBinId, orderBinId))
+          .rpc({ commitment: "confirmed" });
+        const placed = await program.account.position.fetch(orderPda);
+        assert.equal(placed.limitOrder, 1);
+
+        // Not filled yet: the order's bin still holds token A
+        try {
+          await program.methods
+            .claimLimitOrder()
+            .accountsPartial(await positionAccounts(orderMint, orderPda))
+            .remainingAccounts(await binArraysFor(orderBinId, orderBinId))
+            .rpc({ commitment: "confirmed" });
+          assert.fail("claiming an unfilled order should fail");
+        } catch (error) {
+          assert.equal(
+            (error as AnchorError).error.errorCode.code,
+            "LimitOrderNotFilled"
           );
-          swapBinPubkeys.push(binPda);
         }
 
+        // Buy token A up to the order's bin, which converts it entirely to token B
         await program.methods
-          .swap(amountIn, minAmountOut)
-          .accounts({
+          .swap(new BN(100_000_000), new BN(1), orderBinId)
+          .accountsPartial({
             trader: payer.publicKey,
             pool: poolPda,
+            bitmapExtension: null,
+            oracle: oraclePda,
             tokenAMint,
             tokenBMint,
-            sourceVault: tokenAVault,
-            destinationVault: tokenBVault,
-            userSourceTokenAccount: userTokenAAccount,
-            userDestinationTokenAccount: userTokenBAccount,
+            sourceVault: tokenBVault,
+            destinationVault: tokenAVault,
+            userSourceTokenAccount: userTokenBAccount,
+            userDestinationTokenAccount: userTokenAAccount,
             tokenAProgram,
             tokenBProgram,
           })
-          .remainingAccounts(
-            swapBinPubkeys.map((pubkey) => ({
-              pubkey,
-              isWritable: true,
-              isSigner: false,
-            }))
-          )
+          .remainingAccounts(await binArraysFor(activeBinId, orderBinId))
           .rpc({ commitment: "confirmed" });
 
+        const balanceBefore = await getAccount(
+          connection,
+          userTokenBAccount,
+          "confirmed",
+          tokenBProgram
+        );
+        await program.methods
+          .claimLimitOrder()
+          .accountsPartial(await positionAccounts(orderMint, orderPda))
+          .remainingAccounts(await binArraysFor(orderBinId, orderBinId))
+          .rpc({ commitment: "confirmed" });
         const balanceAfter = await getAccount(
           connection,
           userTokenBAccount,
           "confirmed",
           tokenBProgram
         );
+
+        const claimed = await program.account.position.fetch(orderPda);
+        assert.equal(claimed.liquidity.toString(), "0");
+        assert.equal(claimed.limitOrder, 0);
         assert.ok(
           balanceAfter.amount > balanceBefore.amount,
-          "Destination balance should have increased after swap"
+          "The filled order should pay out token B"
         );
       });
     });